@@ -0,0 +1,300 @@
+#![allow(dead_code)]
+
+//! Sockets for async code, which any executor can run.
+//!
+//! Nothing here touches the device: the stack wakes the tasks when it
+//! processes what concerns them. Whoever runs the executor calls `TCP::poll`
+//! when the device is readable or `TCP::poll_at` passes, on the same thread.
+//! With the `tokio` feature, `drive` does so on a Tokio runtime.
+
+use crate::device::Device;
+use crate::socket::{Stack, TcpListener, TcpStream};
+use crate::tcp::{Keepalive, Socket};
+use futures_core::Stream;
+use futures_io::{AsyncRead, AsyncWrite};
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::Shutdown;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Leaves the task to be woken when `result` would block
+fn ready_or_pending<T>(result: io::Result<T>) -> Poll<io::Result<T>> {
+    match result {
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Poll::Pending,
+        result => Poll::Ready(result),
+    }
+}
+
+/// Accepts connections on a port, yielding them as a stream
+pub struct AsyncTcpListener<D: Device> {
+    inner: TcpListener<D>,
+}
+
+impl<D: Device> AsyncTcpListener<D> {
+    /// Binds to `port` and listens on it with the default backlog
+    pub fn bind(stack: &Stack<D>, port: u16) -> io::Result<Self> {
+        Ok(AsyncTcpListener {
+            inner: TcpListener::bind(stack, port)?,
+        })
+    }
+
+    /// Sets how many established connections wait to be accepted
+    pub fn listen(&self, backlog: usize) {
+        self.inner.listen(backlog);
+    }
+
+    /// Takes an established connection, or wakes the task once there is one
+    pub fn poll_accept(&self, cx: &mut Context) -> Poll<io::Result<(AsyncTcpStream<D>, Socket)>> {
+        // Register first, so a connection queued in between still wakes us
+        self.inner.register_acceptor(cx.waker());
+        ready_or_pending(self.inner.try_accept())
+            .map_ok(|(inner, remote)| (AsyncTcpStream { inner }, remote))
+    }
+
+    /// Waits for an established connection
+    pub fn accept(&self) -> Accept<'_, D> {
+        Accept { listener: self }
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.inner.local_port()
+    }
+}
+
+impl<D: Device> Stream for AsyncTcpListener<D> {
+    type Item = io::Result<(AsyncTcpStream<D>, Socket)>;
+
+    /// Never ends: the listener listens until it is dropped
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.poll_accept(cx).map(Some)
+    }
+}
+
+/// Future of `AsyncTcpListener::accept`
+pub struct Accept<'a, D: Device> {
+    listener: &'a AsyncTcpListener<D>,
+}
+
+impl<D: Device> Future for Accept<'_, D> {
+    type Output = io::Result<(AsyncTcpStream<D>, Socket)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.listener.poll_accept(cx)
+    }
+}
+
+/// A connection for async code. Reading and writing never block: they wake
+/// the task once they can go on.
+pub struct AsyncTcpStream<D: Device> {
+    inner: TcpStream<D>,
+}
+
+impl<D: Device> AsyncTcpStream<D> {
+    /// Opens a connection to `remote`, resolving once it is established
+    pub fn connect(stack: &Stack<D>, remote: Socket) -> Connect<D> {
+        Connect {
+            stream: Some(TcpStream::open(stack, remote)),
+        }
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.inner.set_nodelay(nodelay)
+    }
+
+    pub fn set_keepalive(&self, keepalive: Option<Keepalive>) -> io::Result<()> {
+        self.inner.set_keepalive(keepalive)
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    pub fn local_addr(&self) -> Socket {
+        self.inner.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> Socket {
+        self.inner.peer_addr()
+    }
+
+    fn poll_read_inner(&self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        self.inner.register_reader(cx.waker());
+        ready_or_pending(self.inner.try_read(buf))
+    }
+
+    fn poll_write_inner(&self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.inner.register_writer(cx.waker());
+        ready_or_pending(self.inner.try_write(buf))
+    }
+}
+
+/// Future of `AsyncTcpStream::connect`
+pub struct Connect<D: Device> {
+    stream: Option<TcpStream<D>>,
+}
+
+impl<D: Device> Future for Connect<D> {
+    type Output = io::Result<AsyncTcpStream<D>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let stream = self
+            .stream
+            .as_ref()
+            .expect("Connect polled after completion");
+        stream.register_writer(cx.waker());
+        match ready_or_pending(stream.try_connected()) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(result) => {
+                let inner = self.stream.take().unwrap();
+                Poll::Ready(result.map(|_| AsyncTcpStream { inner }))
+            }
+        }
+    }
+}
+
+impl<D: Device> AsyncRead for AsyncTcpStream<D> {
+    /// Reads what was received. Gives 0 once the peer has finished sending.
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_read_inner(cx, buf)
+    }
+}
+
+impl<D: Device> AsyncWrite for AsyncTcpStream<D> {
+    /// Queues as much of `buf` as the send buffer takes
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.poll_write_inner(cx, buf)
+    }
+
+    /// Queued text goes out as the stack sends it, so there is nothing to
+    /// wait for
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Sends a FIN after what was queued
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(self.inner.shutdown(Shutdown::Write))
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[allow(unused_imports)]
+pub use self::tokio_support::drive;
+
+#[cfg(feature = "tokio")]
+mod tokio_support {
+    use super::AsyncTcpStream;
+    use crate::device::Device;
+    use crate::socket::Stack;
+    use std::io;
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::unix::AsyncFd;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio::time::{self, Instant};
+
+    /// The device's file descriptor, which Tokio watches while the stack owns
+    /// the device
+    struct DeviceFd(RawFd);
+
+    impl AsRawFd for DeviceFd {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0
+        }
+    }
+
+    /// Runs `stack` on the current Tokio runtime: processes packets as they
+    /// arrive and timers as they fire, which wakes the tasks on its sockets.
+    /// Returns only when the device fails.
+    ///
+    /// `Stack` is not `Send`, so spawn this with `tokio::task::spawn_local`,
+    /// on a `LocalSet` of a runtime with the `rt` feature.
+    pub async fn drive<D: Device + AsRawFd>(stack: Stack<D>) -> io::Result<()> {
+        let fd = AsyncFd::new(DeviceFd(stack.borrow().iface.as_raw_fd()))?;
+        loop {
+            let poll_at = stack.borrow().poll_at();
+            let readable = fd.readable();
+            let guard = match poll_at {
+                Some(deadline) => time::timeout_at(Instant::from_std(deadline), readable)
+                    .await
+                    .ok(),
+                None => Some(readable.await),
+            };
+            if let Some(mut guard) = guard.transpose()? {
+                // The stack reads the device until it is drained
+                guard.clear_ready();
+            }
+            stack.borrow_mut().poll()?;
+        }
+    }
+
+    impl<D: Device> AsyncRead for AsyncTcpStream<D> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &mut ReadBuf,
+        ) -> Poll<io::Result<()>> {
+            let len = match self.poll_read_inner(cx, buf.initialize_unfilled()) {
+                Poll::Ready(Ok(len)) => len,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            buf.advance(len);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl<D: Device> AsyncWrite for AsyncTcpStream<D> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.poll_write_inner(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(self.inner.shutdown(std::net::Shutdown::Write))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::PipeDevice;
+    use crate::tcp::{Quad, TCP};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::task::Waker;
+
+    #[test]
+    fn dropped_connect_gives_up_on_the_connection() {
+        let (a, b) = PipeDevice::pair();
+        let server = Rc::new(RefCell::new(TCP::with_iface(a, [10, 0, 0, 2])));
+        let client = Rc::new(RefCell::new(TCP::with_iface(b, [10, 0, 0, 1])));
+        let _listener = AsyncTcpListener::bind(&server, 1000).unwrap();
+
+        let mut connect = AsyncTcpStream::connect(&client, ([10, 0, 0, 2], 1000));
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(Pin::new(&mut connect).poll(&mut cx).is_pending());
+        let stream = connect.stream.as_ref().unwrap();
+        let quad = Quad {
+            local: stream.local_addr(),
+            foreign: stream.peer_addr(),
+        };
+
+        drop(connect);
+        assert_eq!(client.borrow().state(&quad), None);
+    }
+}
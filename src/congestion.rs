@@ -0,0 +1,351 @@
+#![allow(dead_code)]
+
+use std::time::{Duration, Instant};
+
+/// Creates the congestion control of every connection a listener accepts
+pub type CongestionFactory = Box<dyn FnMut() -> Box<dyn CongestionControl>>;
+
+/// Decides how much data may be in flight on a connection.
+///
+/// The TCB detects losses and runs fast retransmit and fast recovery; the
+/// algorithm only moves the congestion window in response. All windows and
+/// octet counts are in octets, `mss` is the sender MSS of the connection.
+pub trait CongestionControl {
+    /// Sets the initial window once the MSS is known
+    fn init(&mut self, mss: u32);
+
+    /// Congestion window
+    fn cwnd(&self) -> u32;
+
+    /// `acked` new octets were acknowledged outside of loss recovery
+    fn on_ack(&mut self, acked: u32, mss: u32, rtt: Duration);
+
+    /// A loss was detected from duplicate ACKs or SACKs, and fast recovery
+    /// begins. `flight_size` is the data outstanding when it was detected.
+    fn on_loss(&mut self, flight_size: u32, mss: u32);
+
+    /// Fast recovery ended with everything sent before it being acked
+    fn on_recovery_end(&mut self, flight_size: u32, mss: u32);
+
+    /// The retransmission timer expired
+    fn on_timeout(&mut self, flight_size: u32, mss: u32);
+}
+
+/// Initial window from RFC 5681 3.1
+fn initial_window(mss: u32) -> u32 {
+    (4 * mss).min((2 * mss).max(4380))
+}
+
+/// Slow start threshold after a loss, from RFC 5681 3.1
+fn halve(flight_size: u32, mss: u32) -> u32 {
+    (flight_size / 2).max(2 * mss)
+}
+
+/// Slow start and congestion avoidance of RFC 5681, with the fast recovery
+/// of RFC 6582 exiting on a full acknowledgment
+pub struct NewReno {
+    cwnd: u32,
+    ssthresh: u32,
+}
+
+impl NewReno {
+    pub fn new() -> Self {
+        NewReno {
+            cwnd: 0,
+            // Arbitrarily high, so slow start goes on until the first loss
+            ssthresh: u32::MAX,
+        }
+    }
+}
+
+impl Default for NewReno {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CongestionControl for NewReno {
+    fn init(&mut self, mss: u32) {
+        self.cwnd = initial_window(mss);
+    }
+
+    fn cwnd(&self) -> u32 {
+        self.cwnd
+    }
+
+    fn on_ack(&mut self, acked: u32, mss: u32, _rtt: Duration) {
+        let increase = if self.cwnd < self.ssthresh {
+            acked.min(mss)
+        } else {
+            // About one MSS per RTT
+            (mss * mss / self.cwnd).max(1)
+        };
+        self.cwnd = self.cwnd.saturating_add(increase);
+    }
+
+    fn on_loss(&mut self, flight_size: u32, mss: u32) {
+        self.ssthresh = halve(flight_size, mss);
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_recovery_end(&mut self, flight_size: u32, mss: u32) {
+        // Avoid a burst when little is in flight (RFC 6582 3.2 step 3)
+        self.cwnd = self.ssthresh.min(flight_size.max(mss) + mss);
+    }
+
+    fn on_timeout(&mut self, flight_size: u32, mss: u32) {
+        self.ssthresh = halve(flight_size, mss);
+        // Loss window
+        self.cwnd = mss;
+    }
+}
+
+/// Scaling constant of RFC 8312
+static CUBIC_C: f64 = 0.4;
+/// Multiplicative decrease factor of RFC 8312
+static CUBIC_BETA: f64 = 0.7;
+
+/// CUBIC from RFC 8312: after a loss the window grows along a cubic curve
+/// centered on the window where the loss happened, which makes growth
+/// independent of the RTT
+pub struct Cubic {
+    mss: u32,
+    /// Windows are kept in segments, as in the RFC
+    cwnd: f64,
+    ssthresh: f64,
+    /// Window just before the last reduction
+    w_max: f64,
+    /// `w_max` of the reduction before, for fast convergence
+    w_last_max: f64,
+    /// Start of the current congestion avoidance period
+    epoch_start: Option<Instant>,
+    /// Time the cubic curve takes to get back to `w_max`, in seconds
+    k: f64,
+}
+
+impl Cubic {
+    pub fn new() -> Self {
+        Cubic {
+            mss: 0,
+            cwnd: 0.0,
+            ssthresh: f64::MAX,
+            w_max: 0.0,
+            w_last_max: 0.0,
+            epoch_start: None,
+            k: 0.0,
+        }
+    }
+
+    /// Reduces the window on a loss, and starts a new cubic curve
+    fn reduce(&mut self) {
+        self.epoch_start = None;
+        // Fast convergence: release bandwidth to newer flows (RFC 8312 4.6)
+        self.w_max = if self.cwnd < self.w_last_max {
+            self.cwnd * (1.0 + CUBIC_BETA) / 2.0
+        } else {
+            self.cwnd
+        };
+        self.w_last_max = self.cwnd;
+        self.ssthresh = (self.cwnd * CUBIC_BETA).max(2.0);
+    }
+}
+
+impl Default for Cubic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn init(&mut self, mss: u32) {
+        self.mss = mss;
+        self.cwnd = initial_window(mss) as f64 / mss as f64;
+    }
+
+    fn cwnd(&self) -> u32 {
+        // Casting saturates at u32::MAX
+        (self.cwnd * self.mss as f64) as u32
+    }
+
+    fn on_ack(&mut self, acked: u32, mss: u32, rtt: Duration) {
+        let acked = acked as f64 / mss as f64;
+        if self.cwnd < self.ssthresh {
+            self.cwnd += acked.min(1.0);
+            return;
+        }
+
+        let now = Instant::now();
+        let epoch_start = match self.epoch_start {
+            Some(epoch_start) => epoch_start,
+            None => {
+                if self.cwnd < self.w_max {
+                    self.k = ((self.w_max - self.cwnd) / CUBIC_C).cbrt();
+                } else {
+                    self.k = 0.0;
+                    self.w_max = self.cwnd;
+                }
+                self.epoch_start = Some(now);
+                now
+            }
+        };
+        let t = (now - epoch_start).as_secs_f64();
+        let rtt = rtt.as_secs_f64().max(0.001);
+
+        let w_cubic = |t: f64| CUBIC_C * (t - self.k).powi(3) + self.w_max;
+        // Window standard TCP would have by now (RFC 8312 4.2)
+        let w_est =
+            self.w_max * CUBIC_BETA + 3.0 * (1.0 - CUBIC_BETA) / (1.0 + CUBIC_BETA) * (t / rtt);
+
+        if w_cubic(t) < w_est {
+            self.cwnd = self.cwnd.max(w_est);
+        } else {
+            let target = w_cubic(t + rtt);
+            if target > self.cwnd {
+                self.cwnd += (target - self.cwnd) / self.cwnd * acked;
+            } else {
+                self.cwnd += acked / (100.0 * self.cwnd);
+            }
+        }
+    }
+
+    fn on_loss(&mut self, _flight_size: u32, _mss: u32) {
+        self.reduce();
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_recovery_end(&mut self, _flight_size: u32, _mss: u32) {
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_timeout(&mut self, _flight_size: u32, _mss: u32) {
+        self.reduce();
+        self.cwnd = 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static MSS: u32 = 1000;
+    static RTT: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn initial_window_follows_rfc_5681() {
+        assert_eq!(initial_window(1000), 4000);
+        assert_eq!(initial_window(1460), 4380);
+        assert_eq!(initial_window(2190), 4380);
+        assert_eq!(initial_window(3000), 6000);
+    }
+
+    #[test]
+    fn new_reno_slow_start_grows_by_at_most_an_mss_per_ack() {
+        let mut cc = NewReno::new();
+        cc.init(MSS);
+        cc.on_ack(MSS, MSS, RTT);
+        assert_eq!(cc.cwnd(), 5000);
+        cc.on_ack(3 * MSS, MSS, RTT);
+        assert_eq!(cc.cwnd(), 6000);
+    }
+
+    #[test]
+    fn new_reno_halves_on_loss_and_then_grows_linearly() {
+        let mut cc = NewReno::new();
+        cc.init(MSS);
+        cc.on_loss(10_000, MSS);
+        assert_eq!(cc.cwnd(), 5000);
+
+        // MSS * MSS / cwnd per ACK, about an MSS per window
+        cc.on_ack(MSS, MSS, RTT);
+        assert_eq!(cc.cwnd(), 5200);
+    }
+
+    #[test]
+    fn new_reno_keeps_two_segments_after_a_loss() {
+        let mut cc = NewReno::new();
+        cc.init(MSS);
+        cc.on_loss(MSS, MSS);
+        assert_eq!(cc.cwnd(), 2 * MSS);
+    }
+
+    #[test]
+    fn new_reno_recovery_end_avoids_a_burst() {
+        let mut cc = NewReno::new();
+        cc.init(MSS);
+        cc.on_loss(20_000, MSS);
+        cc.on_recovery_end(20_000, MSS);
+        assert_eq!(cc.cwnd(), 10_000);
+
+        cc.on_loss(20_000, MSS);
+        cc.on_recovery_end(2000, MSS);
+        assert_eq!(cc.cwnd(), 3000);
+    }
+
+    #[test]
+    fn new_reno_timeout_falls_back_to_the_loss_window() {
+        let mut cc = NewReno::new();
+        cc.init(MSS);
+        cc.on_timeout(10_000, MSS);
+        assert_eq!(cc.cwnd(), MSS);
+
+        // Slow start again, up to half the flight size at the timeout
+        for _ in 0..4 {
+            cc.on_ack(MSS, MSS, RTT);
+        }
+        assert_eq!(cc.cwnd(), 5000);
+        cc.on_ack(MSS, MSS, RTT);
+        assert_eq!(cc.cwnd(), 5200);
+    }
+
+    #[test]
+    fn cubic_starts_with_the_initial_window_in_slow_start() {
+        let mut cc = Cubic::new();
+        cc.init(MSS);
+        assert_eq!(cc.cwnd(), 4000);
+        cc.on_ack(MSS, MSS, RTT);
+        assert_eq!(cc.cwnd(), 5000);
+    }
+
+    #[test]
+    fn cubic_reduces_by_beta_on_loss() {
+        let mut cc = Cubic::new();
+        cc.init(MSS);
+        cc.cwnd = 10.0;
+        cc.on_loss(10_000, MSS);
+        assert_eq!(cc.cwnd(), 7000);
+        assert_eq!(cc.w_max, 10.0);
+    }
+
+    #[test]
+    fn cubic_converges_fast_when_losses_come_earlier() {
+        let mut cc = Cubic::new();
+        cc.init(MSS);
+        cc.cwnd = 10.0;
+        cc.on_loss(10_000, MSS);
+        // A loss below the window of the last one releases bandwidth
+        cc.on_loss(7000, MSS);
+        assert!((cc.w_max - 7.0 * (1.0 + CUBIC_BETA) / 2.0).abs() < 1e-9);
+        assert_eq!(cc.w_last_max, 7.0);
+    }
+
+    #[test]
+    fn cubic_curve_returns_to_the_window_of_the_loss() {
+        let mut cc = Cubic::new();
+        cc.init(MSS);
+        cc.cwnd = 10.0;
+        cc.on_loss(10_000, MSS);
+
+        // K = cbrt(W_max * (1 - beta) / C), when the curve is back at W_max
+        cc.on_ack(MSS, MSS, RTT);
+        assert!((cc.k - (10.0 * (1.0 - CUBIC_BETA) / CUBIC_C).cbrt()).abs() < 1e-9);
+        assert!(cc.cwnd() >= 7000 && cc.cwnd() < 10_000);
+    }
+
+    #[test]
+    fn cubic_timeout_falls_back_to_one_segment() {
+        let mut cc = Cubic::new();
+        cc.init(MSS);
+        cc.on_timeout(4000, MSS);
+        assert_eq!(cc.cwnd(), MSS);
+    }
+}
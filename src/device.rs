@@ -0,0 +1,149 @@
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// MTU of a freshly created TUN interface
+static TUN_MTU: usize = 1500;
+/// MTU of an in-memory pipe, same as Ethernet
+static PIPE_MTU: usize = 1500;
+
+/// A link layer the TCP can send and receive IP packets over.
+///
+/// Each call to `recv` yields exactly one IP packet and each call to `send`
+/// transmits exactly one IP packet.
+pub trait Device {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+    fn send(&mut self, packet: &[u8]) -> io::Result<usize>;
+    fn capabilities(&self) -> DeviceCapabilities;
+
+    /// Waits until a packet can be received without blocking, or until
+    /// `timeout` passes. `None` waits forever. Returns whether a packet is
+    /// ready.
+    fn poll(&mut self, timeout: Option<Duration>) -> io::Result<bool>;
+
+    fn mtu(&self) -> usize {
+        self.capabilities().mtu
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceCapabilities {
+    /// Largest IP packet the device can carry, in bytes
+    pub mtu: usize,
+    /// The hardware verified the IPv4 and TCP checksums of received packets
+    /// already, and dropped the bad ones
+    pub checksum_offload: bool,
+}
+
+impl Device for tun_tap::Iface {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        tun_tap::Iface::recv(self, buf)
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<usize> {
+        tun_tap::Iface::send(self, packet)
+    }
+
+    fn poll(&mut self, timeout: Option<Duration>) -> io::Result<bool> {
+        let timeout_ms = match timeout {
+            // Round up, so we don't wake up just before a deadline and spin
+            Some(timeout) => timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+        let mut pollfd = libc::pollfd {
+            fd: self.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        let ret = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(err);
+        }
+        Ok(pollfd.revents & libc::POLLIN != 0)
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        DeviceCapabilities {
+            mtu: TUN_MTU,
+            checksum_offload: false,
+        }
+    }
+}
+
+type PacketQueue = Arc<Mutex<VecDeque<Vec<u8>>>>;
+
+/// One end of an in-memory link between two TCP instances.
+///
+/// Whatever is sent on one end is received on the other end, in order.
+/// `recv` never blocks: it fails with `WouldBlock` when no packet is queued,
+/// so both ends can be ticked alternately from a single thread.
+pub struct PipeDevice {
+    rx: PacketQueue,
+    tx: PacketQueue,
+}
+
+impl PipeDevice {
+    pub fn pair() -> (PipeDevice, PipeDevice) {
+        let a_to_b: PacketQueue = Default::default();
+        let b_to_a: PacketQueue = Default::default();
+        let a = PipeDevice {
+            rx: b_to_a.clone(),
+            tx: a_to_b.clone(),
+        };
+        let b = PipeDevice {
+            rx: a_to_b,
+            tx: b_to_a,
+        };
+        (a, b)
+    }
+
+    /// Number of packets waiting to be received on this end
+    pub fn pending(&self) -> usize {
+        self.rx.lock().unwrap().len()
+    }
+}
+
+impl Device for PipeDevice {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let packet = match self.rx.lock().unwrap().pop_front() {
+            Some(packet) => packet,
+            None => return Err(io::ErrorKind::WouldBlock.into()),
+        };
+        let len = packet.len().min(buf.len());
+        buf[..len].copy_from_slice(&packet[..len]);
+        Ok(len)
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<usize> {
+        if packet.len() > PIPE_MTU {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "packet larger than MTU",
+            ));
+        }
+        self.tx.lock().unwrap().push_back(packet.into());
+        Ok(packet.len())
+    }
+
+    /// The other end is driven from the same thread, so waiting for it would
+    /// never end. Always returns immediately.
+    fn poll(&mut self, _timeout: Option<Duration>) -> io::Result<bool> {
+        Ok(self.pending() > 0)
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        DeviceCapabilities {
+            mtu: PIPE_MTU,
+            checksum_offload: false,
+        }
+    }
+}
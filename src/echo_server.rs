@@ -1,11 +1,11 @@
-use crate::tcp::Service;
 use crate::tcp::Response;
+use crate::tcp::Service;
 
 pub struct EchoServer;
 impl Service for EchoServer {
     fn on_connect(&mut self, data: &[u8]) -> Response {
         println!("Connected: {:?}", data);
-        return Response::Data("Welcome to echo server!".as_bytes().into());
+        Response::Data("Welcome to echo server!".as_bytes().into())
     }
 
     fn on_receive(&mut self, data: &[u8]) -> Response {
@@ -13,7 +13,7 @@ impl Service for EchoServer {
         let mut out = Vec::new();
         out.extend_from_slice("Echo :".as_bytes());
         out.extend_from_slice(data);
-        return Response::Data(out);
+        Response::Push(out)
     }
 
     fn on_reset(&mut self) {
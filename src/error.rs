@@ -0,0 +1,44 @@
+use std::fmt;
+use std::io;
+
+/// Errors of the TCP stack.
+///
+/// Bad packets from the network are no errors: they are dropped and counted.
+/// What is left is whatever keeps the stack from going on.
+#[derive(Debug)]
+pub enum Error {
+    /// The device failed to poll, to receive or to send
+    Device(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Device(e) => write!(f, "Device failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Device(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Device(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Device(e) => e,
+        }
+    }
+}
@@ -1,5 +1,5 @@
-use crate::tcp::Service;
 use crate::tcp::Response;
+use crate::tcp::Service;
 
 pub struct HTTPServer;
 
@@ -54,7 +54,7 @@ HTTP/1.0 404 NOT FOUND
 </body>
 </html>
             "#;
-        return Response::Close(response.as_bytes().into());
+        Response::Close(response.as_bytes().into())
     }
 
     fn on_reset(&mut self) {
@@ -0,0 +1,41 @@
+const TYPE_DESTINATION_UNREACHABLE: u8 = 3;
+const CODE_PORT_UNREACHABLE: u8 = 3;
+
+/// Octets of the original datagram quoted after its IP header, from RFC 792
+const QUOTED_DATA_LEN: usize = 8;
+
+/// Builds an ICMP port unreachable message about `packet`, a received IPv4
+/// packet whose header is `header_len` octets long
+pub fn port_unreachable(packet: &[u8], header_len: usize) -> Vec<u8> {
+    let quoted = packet.len().min(header_len + QUOTED_DATA_LEN);
+
+    // Type, code, checksum and four unused octets
+    let mut message = vec![
+        TYPE_DESTINATION_UNREACHABLE,
+        CODE_PORT_UNREACHABLE,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+    ];
+    message.extend_from_slice(&packet[..quoted]);
+
+    let checksum = checksum(&message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    message
+}
+
+/// 16 bit one's complement of the one's complement sum of `data`, padded
+/// with a zero octet if its length is odd
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
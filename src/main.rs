@@ -1,3 +1,14 @@
+mod async_socket;
+mod congestion;
+mod device;
+mod error;
+mod icmp;
+mod options;
+mod reassembly;
+mod rtt;
+mod seq;
+mod socket;
+
 mod tcp;
 use tcp::*;
 
@@ -7,12 +18,15 @@ use http_server::*;
 mod echo_server;
 use echo_server::*;
 
+#[cfg(test)]
+mod tests;
+
 fn main() {
-    let mut iface = tun_tap::Iface::without_packet_info("", tun_tap::Mode::Tun)
+    let iface = tun_tap::Iface::without_packet_info("", tun_tap::Mode::Tun)
         .expect("Failed to initialize TUN interface");
 
-    let local_socket = ([10, 0, 0, 2], 1000);
-    let foreign_socket = None;
+    let local_addr = [10, 0, 0, 2];
+    let local_port = 1000;
 
     println!("Welcome to TCP demo");
     println!("Choose which server to run: ");
@@ -23,31 +37,23 @@ fn main() {
     std::io::stdin().read_line(&mut input).unwrap();
     let input = input.trim();
 
-    let mut svc: Box<dyn Service> = if input == "1" {
-        Box::new(EchoServer)
+    let make_svc: ServiceFactory = if input == "1" {
+        Box::new(|| Box::new(EchoServer))
     } else if input == "2" {
-        Box::new(HTTPServer)
+        Box::new(|| Box::new(HTTPServer))
     } else {
         println!("Invalid choice");
         return;
     };
 
+    let mut tcp = TCP::with_iface(iface, local_addr);
+    tcp.listen(local_port, make_svc);
+    tcp.set_keepalive(local_port, Some(Keepalive::default()));
+
     loop {
-        let mut tcp = {
-            TCP::with_iface(
-                iface,
-                svc,
-                local_socket,
-                foreign_socket,
-                OpenMode::Passive,
-            )
-        };
-
-        while tcp.tcb.state != TCPState::Closed {
-            tcp.tick();
+        if let Err(e) = tcp.tick() {
+            eprintln!("{}", e);
+            return;
         }
-
-        iface = tcp.iface;
-        svc = tcp.tcb.svc; 
     }
 }
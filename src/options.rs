@@ -0,0 +1,197 @@
+use etherparse::{TcpHeader, TcpOptionElement};
+
+const KIND_END: u8 = 0;
+const KIND_NOP: u8 = 1;
+const KIND_MSS: u8 = 2;
+const KIND_WINDOW_SCALE: u8 = 3;
+const KIND_SACK_PERMITTED: u8 = 4;
+const KIND_SACK: u8 = 5;
+const KIND_TIMESTAMP: u8 = 8;
+
+/// Largest shift a window scale option may carry, from RFC 7323
+pub const MAX_WINDOW_SCALE: u8 = 14;
+
+/// Room for options in a TCP header
+const MAX_OPTIONS_LEN: usize = 40;
+
+/// The options of a segment that we understand.
+///
+/// MSS, window scale and SACK-permitted only mean something on a SYN.
+#[derive(Clone, Default, Debug, Eq, PartialEq)]
+pub struct Options {
+    pub mss: Option<u16>,
+    pub window_scale: Option<u8>,
+    pub sack_permitted: bool,
+    /// TSval and TSecr
+    pub timestamp: Option<(u32, u32)>,
+    /// Left and right edges of SACK blocks
+    pub sack: Vec<(u32, u32)>,
+}
+
+impl Options {
+    /// Reads the options of `tcph`.
+    ///
+    /// Unknown options are skipped by their length. Parsing stops at the
+    /// end of option list or at the first malformed option.
+    pub fn parse(tcph: &TcpHeader) -> Self {
+        let mut options = Options::default();
+        let mut rest = tcph.options();
+        while let Some(&kind) = rest.first() {
+            match kind {
+                KIND_END => break,
+                KIND_NOP => {
+                    rest = &rest[1..];
+                    continue;
+                }
+                _ => {}
+            }
+
+            let len = match rest.get(1) {
+                Some(&len) if len >= 2 && len as usize <= rest.len() => len as usize,
+                _ => break,
+            };
+            let value = &rest[2..len];
+            match (kind, value.len()) {
+                (KIND_MSS, 2) => options.mss = Some(u16::from_be_bytes([value[0], value[1]])),
+                (KIND_WINDOW_SCALE, 1) => {
+                    options.window_scale = Some(value[0].min(MAX_WINDOW_SCALE))
+                }
+                (KIND_SACK_PERMITTED, 0) => options.sack_permitted = true,
+                (KIND_TIMESTAMP, 8) => {
+                    let tsval = u32::from_be_bytes([value[0], value[1], value[2], value[3]]);
+                    let tsecr = u32::from_be_bytes([value[4], value[5], value[6], value[7]]);
+                    options.timestamp = Some((tsval, tsecr));
+                }
+                (KIND_SACK, len) if len % 8 == 0 => {
+                    for edges in value.chunks(8) {
+                        let left = u32::from_be_bytes([edges[0], edges[1], edges[2], edges[3]]);
+                        let right = u32::from_be_bytes([edges[4], edges[5], edges[6], edges[7]]);
+                        options.sack.push((left, right));
+                    }
+                }
+                _ => {}
+            }
+            rest = &rest[len..];
+        }
+        options
+    }
+
+    /// Writes these options into `tcph`, laid out so that they stay aligned.
+    ///
+    /// SACK blocks that don't fit into the header are left out.
+    pub fn write(&self, tcph: &mut TcpHeader) {
+        let mut elements = Vec::new();
+        let mut len = 0;
+        if let Some(mss) = self.mss {
+            elements.push(TcpOptionElement::MaximumSegmentSize(mss));
+            len += 4;
+        }
+        if self.sack_permitted {
+            elements.push(TcpOptionElement::SelectiveAcknowledgementPermitted);
+            len += 2;
+        } else if self.timestamp.is_some() {
+            elements.push(TcpOptionElement::Nop);
+            elements.push(TcpOptionElement::Nop);
+            len += 2;
+        }
+        if let Some((tsval, tsecr)) = self.timestamp {
+            elements.push(TcpOptionElement::Timestamp(tsval, tsecr));
+            len += 10;
+        }
+        if let Some(shift) = self.window_scale {
+            elements.push(TcpOptionElement::Nop);
+            elements.push(TcpOptionElement::WindowScale(shift));
+            len += 4;
+        }
+
+        // Two NOPs, kind and length come before the blocks
+        let room = MAX_OPTIONS_LEN.saturating_sub(len + 4) / 8;
+        let mut blocks = self.sack.iter().take(room.min(4));
+        if let Some(&first) = blocks.next() {
+            let mut rest = [None; 3];
+            for (slot, &block) in rest.iter_mut().zip(blocks) {
+                *slot = Some(block);
+            }
+            elements.push(TcpOptionElement::Nop);
+            elements.push(TcpOptionElement::Nop);
+            elements.push(TcpOptionElement::SelectiveAcknowledgement(first, rest));
+        }
+
+        tcph.set_options(&elements).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(options: &Options) -> TcpHeader {
+        let mut tcph = TcpHeader::new(1000, 2000, 0, 0);
+        options.write(&mut tcph);
+        tcph
+    }
+
+    #[test]
+    fn syn_options_survive_a_round_trip() {
+        let options = Options {
+            mss: Some(1460),
+            window_scale: Some(7),
+            sack_permitted: true,
+            timestamp: Some((1, 0)),
+            sack: Vec::new(),
+        };
+        let tcph = written(&options);
+        assert_eq!(tcph.options().len() % 4, 0);
+        assert_eq!(Options::parse(&tcph), options);
+    }
+
+    #[test]
+    fn sack_blocks_survive_a_round_trip() {
+        let options = Options {
+            timestamp: Some((5, 6)),
+            sack: vec![(100, 200), (300, 400)],
+            ..Options::default()
+        };
+        assert_eq!(Options::parse(&written(&options)), options);
+    }
+
+    #[test]
+    fn sack_blocks_beyond_the_header_are_left_out() {
+        let options = Options {
+            timestamp: Some((5, 6)),
+            sack: vec![(1, 2), (3, 4), (5, 6), (7, 8)],
+            ..Options::default()
+        };
+        // Timestamps leave room for three blocks
+        let parsed = Options::parse(&written(&options));
+        assert_eq!(parsed.sack, &options.sack[..3]);
+    }
+
+    #[test]
+    fn unknown_options_are_skipped() {
+        let mut tcph = TcpHeader::new(1000, 2000, 0, 0);
+        // An unknown kind 30 of length 4, then MSS 536
+        tcph.set_options_raw(&[30, 4, 0, 0, KIND_MSS, 4, 2, 24])
+            .unwrap();
+        assert_eq!(Options::parse(&tcph).mss, Some(536));
+    }
+
+    #[test]
+    fn parsing_stops_at_a_malformed_option() {
+        let mut tcph = TcpHeader::new(1000, 2000, 0, 0);
+        // MSS 536, then an option claiming more octets than there are
+        tcph.set_options_raw(&[KIND_MSS, 4, 2, 24, KIND_WINDOW_SCALE, 9, 0, 0])
+            .unwrap();
+        let options = Options::parse(&tcph);
+        assert_eq!(options.mss, Some(536));
+        assert_eq!(options.window_scale, None);
+    }
+
+    #[test]
+    fn window_scale_is_capped() {
+        let mut tcph = TcpHeader::new(1000, 2000, 0, 0);
+        tcph.set_options_raw(&[KIND_NOP, KIND_WINDOW_SCALE, 3, 20])
+            .unwrap();
+        assert_eq!(Options::parse(&tcph).window_scale, Some(MAX_WINDOW_SCALE));
+    }
+}
@@ -0,0 +1,205 @@
+use crate::seq::SeqNum;
+
+/// Holds text that arrived ahead of RCV.NXT until the gap before it is filled.
+///
+/// Text is kept as a sorted list of non-overlapping, non-adjacent blocks.
+/// Overlapping or adjacent segments are merged into one block as they arrive.
+pub struct Reassembly {
+    blocks: Vec<(SeqNum, Vec<u8>)>,
+    /// Sequence number of the FIN, if one arrived out of order
+    fin: Option<SeqNum>,
+    /// Start of the block that took the latest segment
+    recent: Option<SeqNum>,
+}
+
+impl Reassembly {
+    pub fn new() -> Self {
+        Reassembly {
+            blocks: Vec::new(),
+            fin: None,
+            recent: None,
+        }
+    }
+
+    /// Stores an out of order segment starting at `seq`
+    pub fn insert(&mut self, seq: SeqNum, data: &[u8], fin: bool) {
+        if fin {
+            self.fin = Some(seq + data.len() as u32);
+        }
+        if data.is_empty() {
+            return;
+        }
+
+        let mut start = seq;
+        let mut end = seq + data.len() as u32;
+        let mut touching = Vec::new();
+        let mut i = 0;
+        while i < self.blocks.len() {
+            let (block_start, block) = &self.blocks[i];
+            let block_end = *block_start + block.len() as u32;
+            if block_end >= start && *block_start <= end {
+                if *block_start < start {
+                    start = *block_start;
+                }
+                if block_end > end {
+                    end = block_end;
+                }
+                touching.push(self.blocks.remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        touching.push((seq, data.to_vec()));
+
+        let mut merged = vec![0u8; (end - start) as usize];
+        for (block_start, block) in touching {
+            let offset = (block_start - start) as usize;
+            merged[offset..offset + block.len()].copy_from_slice(&block);
+        }
+
+        let pos = self
+            .blocks
+            .iter()
+            .position(|(block_start, _)| *block_start > start)
+            .unwrap_or(self.blocks.len());
+        self.blocks.insert(pos, (start, merged));
+        self.recent = Some(start);
+    }
+
+    /// Takes the text that continues right from `rcv_nxt`, if the gap before
+    /// it has been filled. Text before `rcv_nxt` is discarded.
+    pub fn pop(&mut self, rcv_nxt: SeqNum) -> Option<Vec<u8>> {
+        match self.blocks.first() {
+            Some((start, _)) if *start <= rcv_nxt => {}
+            _ => return None,
+        }
+
+        let (start, mut block) = self.blocks.remove(0);
+        if self.recent == Some(start) {
+            self.recent = None;
+        }
+        let skip = ((rcv_nxt - start) as usize).min(block.len());
+        block.drain(..skip);
+        Some(block)
+    }
+
+    /// Left and right edges of the blocks held, for SACK options.
+    ///
+    /// The block that took the latest segment comes first, as RFC 2018 asks.
+    pub fn sack_blocks(&self) -> Vec<(SeqNum, SeqNum)> {
+        let mut edges: Vec<_> = self
+            .blocks
+            .iter()
+            .map(|(start, block)| (*start, *start + block.len() as u32))
+            .collect();
+        if let Some(pos) = edges
+            .iter()
+            .position(|(start, _)| Some(*start) == self.recent)
+        {
+            let recent = edges.remove(pos);
+            edges.insert(0, recent);
+        }
+        edges
+    }
+
+    /// Whether no text is held, so there is no gap in the sequence space
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Whether the FIN is the next thing after `rcv_nxt`
+    pub fn fin_reached(&self, rcv_nxt: SeqNum) -> bool {
+        self.fin == Some(rcv_nxt)
+    }
+}
+
+impl Default for Reassembly {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_for_the_gap_to_fill() {
+        let mut reassembly = Reassembly::new();
+        reassembly.insert(SeqNum(110), b"world", false);
+        assert!(!reassembly.is_empty());
+        assert_eq!(reassembly.pop(SeqNum(100)), None);
+
+        // The gap 100..110 was filled by in-order text
+        assert_eq!(reassembly.pop(SeqNum(110)), Some(b"world".to_vec()));
+        assert!(reassembly.is_empty());
+    }
+
+    #[test]
+    fn overlapping_segments_are_merged() {
+        let mut reassembly = Reassembly::new();
+        reassembly.insert(SeqNum(10), b"abcdef", false);
+        reassembly.insert(SeqNum(14), b"efgh", false);
+        reassembly.insert(SeqNum(8), b"xyab", false);
+        assert_eq!(reassembly.sack_blocks(), vec![(SeqNum(8), SeqNum(18))]);
+        assert_eq!(reassembly.pop(SeqNum(8)), Some(b"xyabcdefgh".to_vec()));
+    }
+
+    #[test]
+    fn adjacent_segments_are_merged() {
+        let mut reassembly = Reassembly::new();
+        reassembly.insert(SeqNum(10), b"abc", false);
+        reassembly.insert(SeqNum(13), b"def", false);
+        assert_eq!(reassembly.sack_blocks(), vec![(SeqNum(10), SeqNum(16))]);
+    }
+
+    #[test]
+    fn a_segment_bridging_two_blocks_merges_them() {
+        let mut reassembly = Reassembly::new();
+        reassembly.insert(SeqNum(10), b"ab", false);
+        reassembly.insert(SeqNum(20), b"kl", false);
+        reassembly.insert(SeqNum(11), b"bcdefghijk", false);
+        assert_eq!(reassembly.pop(SeqNum(10)), Some(b"abcdefghijkl".to_vec()));
+        assert!(reassembly.is_empty());
+    }
+
+    #[test]
+    fn text_before_rcv_nxt_is_discarded() {
+        let mut reassembly = Reassembly::new();
+        reassembly.insert(SeqNum(10), b"abcdef", false);
+        assert_eq!(reassembly.pop(SeqNum(13)), Some(b"def".to_vec()));
+    }
+
+    #[test]
+    fn wraps_around_sequence_space() {
+        let mut reassembly = Reassembly::new();
+        reassembly.insert(SeqNum(2), b"cd", false);
+        reassembly.insert(SeqNum(u32::MAX), b"zab", false);
+        assert_eq!(reassembly.pop(SeqNum(u32::MAX)), Some(b"zabcd".to_vec()));
+    }
+
+    #[test]
+    fn latest_block_comes_first_in_sack() {
+        let mut reassembly = Reassembly::new();
+        reassembly.insert(SeqNum(10), b"a", false);
+        reassembly.insert(SeqNum(30), b"c", false);
+        reassembly.insert(SeqNum(20), b"b", false);
+        assert_eq!(
+            reassembly.sack_blocks(),
+            vec![
+                (SeqNum(20), SeqNum(21)),
+                (SeqNum(10), SeqNum(11)),
+                (SeqNum(30), SeqNum(31)),
+            ]
+        );
+    }
+
+    #[test]
+    fn fin_follows_the_text_before_it() {
+        let mut reassembly = Reassembly::new();
+        reassembly.insert(SeqNum(10), b"abc", true);
+        assert!(!reassembly.fin_reached(SeqNum(10)));
+        reassembly.pop(SeqNum(10));
+        assert!(reassembly.fin_reached(SeqNum(13)));
+    }
+}
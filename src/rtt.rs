@@ -0,0 +1,134 @@
+use std::time::Duration;
+
+/// RTO before the first RTT measurement, from RFC 6298
+static INITIAL_RTO: Duration = Duration::from_secs(1);
+static MIN_RTO: Duration = Duration::from_secs(1);
+static MAX_RTO: Duration = Duration::from_secs(60);
+/// Clock granularity G of RFC 6298
+static CLOCK_GRANULARITY: Duration = Duration::from_millis(1);
+
+/// Estimates the retransmission timeout from measured round trip times,
+/// as described in RFC 6298.
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        RttEstimator {
+            srtt: None,
+            rttvar: Duration::from_secs(0),
+            rto: INITIAL_RTO,
+        }
+    }
+
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Smoothed RTT, once there is a measurement
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Updates the estimate with a new RTT measurement.
+    ///
+    /// Callers must not sample segments that were retransmitted (Karn's
+    /// algorithm), since their ACK can't be matched to a transmission.
+    pub fn sample(&mut self, rtt: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            }
+            Some(srtt) => {
+                self.rttvar = self.rttvar * 3 / 4 + srtt.abs_diff(rtt) / 4;
+                srtt * 7 / 8 + rtt / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto = (srtt + CLOCK_GRANULARITY.max(self.rttvar * 4)).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Interval of the persist timer after `probes` window probes went
+    /// unanswered by an open window, backed off like the RTO
+    pub fn persist_timeout(&self, probes: u32) -> Duration {
+        self.rto
+            .saturating_mul(1 << probes.min(16))
+            .clamp(MIN_RTO, MAX_RTO)
+    }
+
+    /// Doubles the RTO after the retransmission timer expired
+    pub fn backoff(&mut self) {
+        self.rto = (self.rto * 2).min(MAX_RTO);
+    }
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_measurement_sets_the_estimate() {
+        let mut rtt = RttEstimator::new();
+        assert_eq!(rtt.rto(), INITIAL_RTO);
+        assert_eq!(rtt.srtt(), None);
+
+        // SRTT = R, RTTVAR = R/2, RTO = SRTT + 4 RTTVAR
+        rtt.sample(Duration::from_secs(2));
+        assert_eq!(rtt.srtt(), Some(Duration::from_secs(2)));
+        assert_eq!(rtt.rto(), Duration::from_secs(6));
+    }
+
+    #[test]
+    fn later_measurements_are_smoothed() {
+        let mut rtt = RttEstimator::new();
+        rtt.sample(Duration::from_secs(2));
+        rtt.sample(Duration::from_secs(1));
+
+        // RTTVAR = 3/4 * 1s + 1/4 * |2s - 1s| = 1s, SRTT = 7/8 * 2s + 1/8 * 1s
+        assert_eq!(rtt.srtt(), Some(Duration::from_millis(1875)));
+        assert_eq!(rtt.rto(), Duration::from_millis(5875));
+    }
+
+    #[test]
+    fn rto_is_clamped() {
+        let mut rtt = RttEstimator::new();
+        rtt.sample(Duration::from_millis(10));
+        assert_eq!(rtt.rto(), MIN_RTO);
+
+        rtt.sample(Duration::from_secs(100));
+        assert_eq!(rtt.rto(), MAX_RTO);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let mut rtt = RttEstimator::new();
+        rtt.backoff();
+        assert_eq!(rtt.rto(), INITIAL_RTO * 2);
+        for _ in 0..10 {
+            rtt.backoff();
+        }
+        assert_eq!(rtt.rto(), MAX_RTO);
+
+        // A new measurement ends the backoff
+        rtt.sample(Duration::from_secs(2));
+        assert_eq!(rtt.rto(), Duration::from_secs(6));
+    }
+
+    #[test]
+    fn persist_timeout_backs_off() {
+        let rtt = RttEstimator::new();
+        assert_eq!(rtt.persist_timeout(0), INITIAL_RTO);
+        assert_eq!(rtt.persist_timeout(3), INITIAL_RTO * 8);
+        assert_eq!(rtt.persist_timeout(100), MAX_RTO);
+    }
+}
@@ -0,0 +1,139 @@
+use crate::tcp::Quad;
+use std::cmp::Ordering;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::ops::{Add, AddAssign, Sub};
+use std::time::Instant;
+
+/// A TCP sequence number.
+///
+/// Sequence space wraps around at 2^32, so all arithmetic is modulo 2^32 and
+/// comparisons follow RFC 1982: `a < b` iff `b` is less than 2^31 ahead of `a`.
+/// This makes `<` meaningful for any two numbers within half the sequence
+/// space of each other, which the windows in a TCB always are.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Default, Debug)]
+pub struct SeqNum(pub u32);
+
+impl Add<u32> for SeqNum {
+    type Output = SeqNum;
+
+    fn add(self, rhs: u32) -> SeqNum {
+        SeqNum(self.0.wrapping_add(rhs))
+    }
+}
+
+impl AddAssign<u32> for SeqNum {
+    fn add_assign(&mut self, rhs: u32) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<u32> for SeqNum {
+    type Output = SeqNum;
+
+    fn sub(self, rhs: u32) -> SeqNum {
+        SeqNum(self.0.wrapping_sub(rhs))
+    }
+}
+
+/// Number of octets from `rhs` up to `self`
+impl Sub<SeqNum> for SeqNum {
+    type Output = u32;
+
+    fn sub(self, rhs: SeqNum) -> u32 {
+        self.0.wrapping_sub(rhs.0)
+    }
+}
+
+impl PartialOrd for SeqNum {
+    fn partial_cmp(&self, other: &SeqNum) -> Option<Ordering> {
+        Some((self.0.wrapping_sub(other.0) as i32).cmp(&0))
+    }
+}
+
+/// Picks the initial sequence number of a new connection
+pub trait IsnGenerator {
+    fn isn(&mut self, quad: &Quad) -> SeqNum;
+}
+
+/// Any closure can be used as a generator, eg. a constant one in tests
+impl<F: FnMut(&Quad) -> SeqNum> IsnGenerator for F {
+    fn isn(&mut self, quad: &Quad) -> SeqNum {
+        self(quad)
+    }
+}
+
+/// ISN generator from RFC 6528: `ISN = M + F(4-tuple, secret)`.
+///
+/// `M` is a timer ticking every 4 microseconds, so successive connections
+/// with the same 4-tuple get increasing ISNs. `F` is a keyed hash of the
+/// 4-tuple, so ISNs can't be guessed from another connection's ISN.
+pub struct Rfc6528Isn {
+    start: Instant,
+    /// SipHash keys, chosen randomly once per process
+    secret: RandomState,
+}
+
+impl Rfc6528Isn {
+    pub fn new() -> Self {
+        Rfc6528Isn {
+            start: Instant::now(),
+            secret: RandomState::new(),
+        }
+    }
+}
+
+impl Default for Rfc6528Isn {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IsnGenerator for Rfc6528Isn {
+    fn isn(&mut self, quad: &Quad) -> SeqNum {
+        // Truncating to u32 makes the timer wrap around with sequence space
+        let m = (self.start.elapsed().as_micros() / 4) as u32;
+
+        let f = self.secret.hash_one(quad) as u32;
+
+        SeqNum(m) + f
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addition_wraps_around() {
+        assert_eq!(SeqNum(u32::MAX) + 2, SeqNum(1));
+        assert_eq!(SeqNum(1) - 2, SeqNum(u32::MAX));
+
+        let mut seq = SeqNum(u32::MAX - 1);
+        seq += 3;
+        assert_eq!(seq, SeqNum(1));
+    }
+
+    #[test]
+    fn distance_across_wraparound() {
+        assert_eq!(SeqNum(5) - SeqNum(u32::MAX - 10), 16);
+        assert_eq!(SeqNum(100) - SeqNum(40), 60);
+    }
+
+    #[test]
+    fn comparison_across_wraparound() {
+        let before = SeqNum(u32::MAX - 10);
+        let after = SeqNum(5);
+        assert!(before < after);
+        assert!(after > before);
+        assert!(before <= before);
+        assert_eq!(after.partial_cmp(&before), Some(Ordering::Greater));
+    }
+
+    #[test]
+    fn comparison_within_half_the_space() {
+        let seq = SeqNum(123_445);
+        assert!(seq < seq + (1 << 31) - 1);
+        assert!(seq > seq - ((1 << 31) - 1));
+    }
+}
@@ -0,0 +1,597 @@
+#![allow(dead_code)]
+
+use crate::device::Device;
+use crate::tcp::{Keepalive, Quad, Response, Service, Socket, TCP};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::rc::Rc;
+use std::task::Waker;
+
+/// A TCP shared by the sockets on it. Sockets tick it while they wait.
+pub type Stack<D> = Rc<RefCell<TCP<D>>>;
+
+/// Received text a socket holds until it is read. Beyond that the window
+/// closes.
+static SOCKET_BUFFER_SIZE: usize = 8192;
+/// Established connections a listener holds until they are accepted
+static DEFAULT_BACKLOG: usize = 128;
+
+/// What a connection's service and its stream share
+#[derive(Default)]
+struct Shared {
+    quad: Option<Quad>,
+    received: VecDeque<u8>,
+    connected: bool,
+    /// The peer finished sending
+    eof: bool,
+    reset: bool,
+    closed: bool,
+    /// Reading was shut down, so received text is dropped
+    read_shutdown: bool,
+    /// Nobody will read anymore: neither a stream nor the accept queue
+    /// holds the connection
+    detached: bool,
+    /// Task waiting for text or the end of it
+    reader: Option<Waker>,
+    /// Task waiting for the connection to open or for send buffer space
+    writer: Option<Waker>,
+}
+
+impl Shared {
+    fn wake_reader(&mut self) {
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(waker) = self.writer.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct AcceptQueue {
+    established: VecDeque<Rc<RefCell<Shared>>>,
+    backlog: usize,
+    /// Task waiting for a connection to accept
+    acceptor: Option<Waker>,
+}
+
+/// Serves a connection by buffering what arrives for its socket, which
+/// writes by itself
+struct SocketService {
+    shared: Rc<RefCell<Shared>>,
+    /// Where an accepted connection goes once established
+    accept_queue: Option<Rc<RefCell<AcceptQueue>>>,
+}
+
+impl Service for SocketService {
+    fn on_open(&mut self, quad: Quad) {
+        self.shared.borrow_mut().quad = Some(quad);
+    }
+
+    fn on_connect(&mut self, _data: &[u8]) -> Response {
+        let mut shared = self.shared.borrow_mut();
+        shared.connected = true;
+        shared.wake_writer();
+        if let Some(queue) = &self.accept_queue {
+            let mut queue = queue.borrow_mut();
+            if queue.established.len() >= queue.backlog {
+                shared.detached = true;
+                return Response::Close(Vec::new());
+            }
+            queue.established.push_back(self.shared.clone());
+            if let Some(waker) = queue.acceptor.take() {
+                waker.wake();
+            }
+        }
+        Response::None
+    }
+
+    fn on_receive(&mut self, data: &[u8]) -> Response {
+        let mut shared = self.shared.borrow_mut();
+        if !shared.detached && !shared.read_shutdown {
+            shared.received.extend(data);
+            shared.wake_reader();
+        }
+        Response::None
+    }
+
+    fn on_reset(&mut self) {
+        let mut shared = self.shared.borrow_mut();
+        shared.reset = true;
+        shared.wake_reader();
+        shared.wake_writer();
+    }
+
+    fn on_close(&mut self, _data: &[u8]) {
+        let mut shared = self.shared.borrow_mut();
+        shared.closed = true;
+        shared.wake_reader();
+        shared.wake_writer();
+    }
+
+    fn on_sent(&mut self) {
+        self.shared.borrow_mut().wake_writer();
+    }
+
+    fn on_fin(&mut self) -> Response {
+        let mut shared = self.shared.borrow_mut();
+        shared.eof = true;
+        shared.wake_reader();
+        // The socket closes its side when it is done writing
+        if shared.detached {
+            Response::Close(Vec::new())
+        } else {
+            Response::None
+        }
+    }
+
+    fn receive_capacity(&self) -> usize {
+        let shared = self.shared.borrow();
+        if shared.detached || shared.read_shutdown {
+            usize::MAX
+        } else {
+            SOCKET_BUFFER_SIZE.saturating_sub(shared.received.len())
+        }
+    }
+}
+
+/// Accepts connections on a port, like `std::net::TcpListener`
+pub struct TcpListener<D: Device> {
+    stack: Stack<D>,
+    port: u16,
+    queue: Rc<RefCell<AcceptQueue>>,
+    nonblocking: bool,
+}
+
+impl<D: Device> TcpListener<D> {
+    /// Binds to `port` and listens on it with the default backlog
+    pub fn bind(stack: &Stack<D>, port: u16) -> io::Result<Self> {
+        let mut tcp = stack.borrow_mut();
+        if tcp.is_listening(port) {
+            return Err(ErrorKind::AddrInUse.into());
+        }
+
+        let queue = Rc::new(RefCell::new(AcceptQueue {
+            established: VecDeque::new(),
+            backlog: DEFAULT_BACKLOG,
+            acceptor: None,
+        }));
+        let accept_queue = queue.clone();
+        tcp.listen(
+            port,
+            Box::new(move || {
+                Box::new(SocketService {
+                    shared: Default::default(),
+                    accept_queue: Some(accept_queue.clone()),
+                })
+            }),
+        );
+
+        Ok(TcpListener {
+            stack: stack.clone(),
+            port,
+            queue,
+            nonblocking: false,
+        })
+    }
+
+    /// Sets how many established connections wait to be accepted. Those
+    /// beyond are closed right away.
+    pub fn listen(&self, backlog: usize) {
+        self.queue.borrow_mut().backlog = backlog;
+    }
+
+    /// Waits for an established connection, and returns it with the socket
+    /// of the peer
+    pub fn accept(&self) -> io::Result<(TcpStream<D>, Socket)> {
+        loop {
+            match self.try_accept() {
+                Err(e) if e.kind() == ErrorKind::WouldBlock && !self.nonblocking => {
+                    self.stack.borrow_mut().tick()?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Takes an established connection without waiting for one, else fails
+    /// with `WouldBlock`
+    pub(crate) fn try_accept(&self) -> io::Result<(TcpStream<D>, Socket)> {
+        let shared = self
+            .queue
+            .borrow_mut()
+            .established
+            .pop_front()
+            .ok_or(ErrorKind::WouldBlock)?;
+        let quad = shared.borrow().quad.expect("Opened before established");
+        let stream = TcpStream {
+            stack: self.stack.clone(),
+            quad,
+            shared,
+            nonblocking: self.nonblocking,
+        };
+        Ok((stream, quad.foreign))
+    }
+
+    /// Wakes `waker` once a connection can be accepted
+    pub(crate) fn register_acceptor(&self, waker: &Waker) {
+        self.queue.borrow_mut().acceptor = Some(waker.clone());
+    }
+
+    pub(crate) fn stack(&self) -> &Stack<D> {
+        &self.stack
+    }
+
+    /// Makes `accept` fail with `WouldBlock` instead of waiting
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl<D: Device> Drop for TcpListener<D> {
+    /// Stops listening, and resets the connections nobody accepted
+    fn drop(&mut self) {
+        let mut tcp = self.stack.borrow_mut();
+        tcp.unlisten(self.port);
+        let established: Vec<_> = self.queue.borrow_mut().established.drain(..).collect();
+        for shared in established {
+            let quad = {
+                let mut shared = shared.borrow_mut();
+                shared.detached = true;
+                shared.quad
+            };
+            // Resetting tells the service, which needs `shared` itself
+            if let Some(quad) = quad {
+                let _ = tcp.reset(&quad);
+            }
+        }
+    }
+}
+
+/// A connection, like `std::net::TcpStream`
+pub struct TcpStream<D: Device> {
+    stack: Stack<D>,
+    quad: Quad,
+    shared: Rc<RefCell<Shared>>,
+    nonblocking: bool,
+}
+
+impl<D: Device> TcpStream<D> {
+    /// Opens a connection to `remote`, and waits until it is established
+    pub fn connect(stack: &Stack<D>, remote: Socket) -> io::Result<Self> {
+        let stream = Self::open(stack, remote);
+        loop {
+            match stream.try_connected() {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => stack.borrow_mut().tick()?,
+                result => return result.map(|_| stream),
+            }
+        }
+    }
+
+    /// Sends a SYN to `remote`, without waiting for the connection to be
+    /// established
+    pub(crate) fn open(stack: &Stack<D>, remote: Socket) -> Self {
+        let shared: Rc<RefCell<Shared>> = Default::default();
+        let svc = SocketService {
+            shared: shared.clone(),
+            accept_queue: None,
+        };
+        let quad = stack.borrow_mut().connect(remote, Box::new(svc));
+        TcpStream {
+            stack: stack.clone(),
+            quad,
+            shared,
+            nonblocking: false,
+        }
+    }
+
+    /// Whether the connection is established yet, failing with `WouldBlock`
+    /// while it is not
+    pub(crate) fn try_connected(&self) -> io::Result<()> {
+        let shared = self.shared.borrow();
+        if shared.connected {
+            Ok(())
+        } else if shared.reset || shared.closed {
+            Err(ErrorKind::ConnectionRefused.into())
+        } else {
+            Err(ErrorKind::WouldBlock.into())
+        }
+    }
+
+    /// Shuts down reading, writing or both. Shutting down writing sends our
+    /// FIN once everything written is sent.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if let Shutdown::Read | Shutdown::Both = how {
+            let mut shared = self.shared.borrow_mut();
+            shared.read_shutdown = true;
+            shared.received.clear();
+        }
+        if let Shutdown::Write | Shutdown::Both = how {
+            self.stack.borrow_mut().shutdown(&self.quad)?;
+        }
+        Ok(())
+    }
+
+    /// Closes the connection, same as dropping the stream
+    pub fn close(self) {}
+
+    /// Makes reads and writes fail with `WouldBlock` instead of waiting
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    /// Turns Nagle's algorithm off, or back on
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.stack
+            .borrow_mut()
+            .set_connection_nodelay(&self.quad, nodelay)
+    }
+
+    /// Sends keepalives while the connection is idle, or stops with `None`
+    pub fn set_keepalive(&self, keepalive: Option<Keepalive>) -> io::Result<()> {
+        self.stack
+            .borrow_mut()
+            .set_connection_keepalive(&self.quad, keepalive)
+    }
+
+    pub fn local_addr(&self) -> Socket {
+        self.quad.local
+    }
+
+    pub fn peer_addr(&self) -> Socket {
+        self.quad.foreign
+    }
+
+    /// Reads what was received without waiting, else fails with
+    /// `WouldBlock`. Returns 0 once the peer has finished sending.
+    pub(crate) fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut shared = self.shared.borrow_mut();
+        if !shared.received.is_empty() {
+            let len = buf.len().min(shared.received.len());
+            for (dst, src) in buf.iter_mut().zip(shared.received.drain(..len)) {
+                *dst = src;
+            }
+            drop(shared);
+            // The stack may hold text back for want of room, and the peer
+            // waits for the window to open. Nothing else polls the stack for
+            // that, so the reader lets it know.
+            let _ = self.stack.borrow_mut().drain(&self.quad);
+            Ok(len)
+        } else if shared.reset {
+            Err(ErrorKind::ConnectionReset.into())
+        } else if shared.eof || shared.closed || shared.read_shutdown || buf.is_empty() {
+            Ok(0)
+        } else {
+            Err(ErrorKind::WouldBlock.into())
+        }
+    }
+
+    /// Queues as much of `buf` as the send buffer takes without waiting,
+    /// else fails with `WouldBlock`
+    pub(crate) fn try_write(&self, buf: &[u8]) -> io::Result<usize> {
+        if self.shared.borrow().reset {
+            return Err(ErrorKind::ConnectionReset.into());
+        }
+        match self.stack.borrow_mut().send(&self.quad, buf)? {
+            0 if !buf.is_empty() => Err(ErrorKind::WouldBlock.into()),
+            len => Ok(len),
+        }
+    }
+
+    /// Wakes `waker` once there is text or the end of it to read
+    pub(crate) fn register_reader(&self, waker: &Waker) {
+        self.shared.borrow_mut().reader = Some(waker.clone());
+    }
+
+    /// Wakes `waker` once the connection opens, or the send buffer has room
+    pub(crate) fn register_writer(&self, waker: &Waker) {
+        self.shared.borrow_mut().writer = Some(waker.clone());
+    }
+
+    /// Retries `op` while it would block, ticking the stack in between,
+    /// unless the stream doesn't wait
+    fn blocking<T>(&self, mut op: impl FnMut(&Self) -> io::Result<T>) -> io::Result<T> {
+        loop {
+            match op(self) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock && !self.nonblocking => {
+                    self.stack.borrow_mut().tick()?;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<D: Device> Read for TcpStream<D> {
+    /// Reads what was received, or waits for text. Returns 0 once the peer
+    /// has finished sending.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.blocking(|stream| stream.try_read(buf))
+    }
+}
+
+impl<D: Device> Write for TcpStream<D> {
+    /// Queues as much of `buf` as the send buffer takes, or waits for room
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.blocking(|stream| stream.try_write(buf))
+    }
+
+    /// Written text is on its way already
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<D: Device> Drop for TcpStream<D> {
+    /// Closes our side of the connection. Text received but not read
+    /// resets it instead, so the peer learns it was lost (RFC 1122 4.2.2.13).
+    /// A connection that isn't established yet is given up on.
+    fn drop(&mut self) {
+        let (connected, unread) = {
+            let mut shared = self.shared.borrow_mut();
+            shared.detached = true;
+            if shared.reset || shared.closed {
+                return;
+            }
+            (shared.connected, !shared.received.is_empty())
+        };
+        // The service is told about what follows, and needs `shared` itself
+        let mut tcp = self.stack.borrow_mut();
+        let _ = if unread || !connected {
+            tcp.reset(&self.quad)
+        } else {
+            tcp.shutdown(&self.quad)
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::PipeDevice;
+
+    static SERVER_ADDR: [u8; 4] = [10, 0, 0, 2];
+    static CLIENT_ADDR: [u8; 4] = [10, 0, 0, 1];
+    static PORT: u16 = 1000;
+
+    fn stacks() -> (Stack<PipeDevice>, Stack<PipeDevice>) {
+        let (a, b) = PipeDevice::pair();
+        (
+            Rc::new(RefCell::new(TCP::with_iface(a, SERVER_ADDR))),
+            Rc::new(RefCell::new(TCP::with_iface(b, CLIENT_ADDR))),
+        )
+    }
+
+    fn poll(server: &Stack<PipeDevice>, client: &Stack<PipeDevice>) {
+        server.borrow_mut().poll().unwrap();
+        client.borrow_mut().poll().unwrap();
+    }
+
+    /// Opens a connection from `client` to a listener on `server`, and
+    /// returns the accepted stream and the client's
+    fn connected(
+        server: &Stack<PipeDevice>,
+        client: &Stack<PipeDevice>,
+    ) -> (TcpStream<PipeDevice>, TcpStream<PipeDevice>) {
+        let listener = TcpListener::bind(server, PORT).unwrap();
+        let stream = TcpStream::open(client, (SERVER_ADDR, PORT));
+        for _ in 0..10 {
+            poll(server, client);
+            if let Ok((accepted, _)) = listener.try_accept() {
+                return (accepted, stream);
+            }
+        }
+        panic!("Not connected");
+    }
+
+    /// Polls both stacks until `done`, failing if that takes long
+    fn run(server: &Stack<PipeDevice>, client: &Stack<PipeDevice>, mut done: impl FnMut() -> bool) {
+        for _ in 0..100 {
+            if done() {
+                return;
+            }
+            poll(server, client);
+        }
+        panic!("Timed out");
+    }
+
+    #[test]
+    fn accepted_stream_talks_to_the_connecting_one() {
+        let (server, client) = stacks();
+        let (accepted, stream) = connected(&server, &client);
+        assert_eq!(accepted.local_addr(), stream.peer_addr());
+        assert_eq!(accepted.peer_addr(), stream.local_addr());
+
+        stream.try_write(b"ping").unwrap();
+        let mut buf = [0; 16];
+        let mut read = Err(ErrorKind::WouldBlock.into());
+        run(&server, &client, || {
+            read = accepted.try_read(&mut buf);
+            read.is_ok()
+        });
+        assert_eq!(&buf[..read.unwrap()], b"ping");
+
+        // Closing the stream reads as the end of the text
+        drop(stream);
+        run(&server, &client, || {
+            accepted.try_read(&mut buf).ok() == Some(0)
+        });
+    }
+
+    #[test]
+    fn stream_dropped_before_connecting_is_forgotten() {
+        let (server, client) = stacks();
+        let _listener = TcpListener::bind(&server, PORT).unwrap();
+        let stream = TcpStream::open(&client, (SERVER_ADDR, PORT));
+        let quad = stream.quad;
+        drop(stream);
+        assert_eq!(client.borrow().state(&quad), None);
+    }
+
+    #[test]
+    fn dropping_unread_text_resets_the_peer() {
+        let (server, client) = stacks();
+        let (accepted, stream) = connected(&server, &client);
+        accepted.try_write(b"unread").unwrap();
+        run(&server, &client, || {
+            !stream.shared.borrow().received.is_empty()
+        });
+
+        // Unlike a FIN, which leaves it in CLOSE-WAIT, the RST ends the
+        // peer's connection
+        drop(stream);
+        run(&server, &client, || {
+            server.borrow().state(&accepted.quad).is_none()
+        });
+    }
+
+    #[test]
+    fn dropped_listener_resets_connections_not_accepted() {
+        let (server, client) = stacks();
+        let listener = TcpListener::bind(&server, PORT).unwrap();
+        let stream = TcpStream::open(&client, (SERVER_ADDR, PORT));
+        run(&server, &client, || stream.try_connected().is_ok());
+        // Queued once the handshake is over on the server side too
+        run(&server, &client, || {
+            !listener.queue.borrow().established.is_empty()
+        });
+
+        drop(listener);
+        run(&server, &client, || {
+            client.borrow().state(&stream.quad).is_none()
+        });
+    }
+
+    #[test]
+    fn late_reader_gets_the_text_held_back() {
+        let (server, client) = stacks();
+        let (accepted, mut stream) = connected(&server, &client);
+        stream.set_nonblocking(true);
+
+        // More than the socket buffer, so the stack holds the rest back
+        let text = vec![b'x'; 12000];
+        let mut written = 0;
+        for _ in 0..100 {
+            written += accepted.try_write(&text[written..]).unwrap_or(0);
+            poll(&server, &client);
+        }
+        assert_eq!(written, text.len());
+
+        // Nothing polls the client stack while it reads
+        let mut read = 0;
+        let mut buf = [0; 1000];
+        while let Ok(len) = stream.try_read(&mut buf) {
+            read += len;
+        }
+        assert_eq!(read, text.len());
+    }
+}
@@ -1,51 +1,222 @@
 #![allow(dead_code)]
 
+use crate::congestion::{CongestionControl, CongestionFactory, NewReno};
+use crate::device::Device;
+use crate::error::Result;
+use crate::icmp;
+use crate::options::{Options, MAX_WINDOW_SCALE};
+use crate::reassembly::Reassembly;
+use crate::rtt::RttEstimator;
+use crate::seq::{IsnGenerator, Rfc6528Isn, SeqNum};
 use etherparse::{IpTrafficClass, Ipv4Header, TcpHeader};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind};
 use std::time::{Duration, Instant};
 
 /// IP Time To Live default taken from Wikipedia
 static IP_TTL: u8 = 64;
-static TIMEOUT_RETR: Duration = Duration::from_secs(5);
 static TIMEOUT_2MSL: Duration = Duration::from_secs(5);
+/// Retransmissions of a segment before the connection is given up
+static MAX_RETRANSMISSIONS: u32 = 8;
+/// Start of the dynamic port range from RFC 6335
+static EPHEMERAL_PORT_START: u16 = 49152;
+/// Default receive buffer size of a connection
+static RECV_BUFFER_SIZE: usize = 8192;
+/// Text `send` queues on a connection before it pushes back
+static SEND_BUFFER_SIZE: usize = 8192;
+/// MSS assumed when the peer doesn't tell, from RFC 1122
+static DEFAULT_MSS: usize = 536;
+/// Smallest MSS we go along with, so segments have room for text after
+/// the options
+static MIN_MSS: usize = 88;
+/// IPv4 and TCP headers without options
+static HEADERS_LEN: usize = 40;
+/// SACKed segments above a hole before it counts as lost, from RFC 6675
+static DUP_THRESH: usize = 3;
+/// Default time an ACK is held back, waiting for something to ride on
+static ACK_DELAY: Duration = Duration::from_millis(200);
+/// Longest an ACK may be delayed, from RFC 1122
+static MAX_ACK_DELAY: Duration = Duration::from_millis(500);
+/// Half-open connections a listener holds in SYN-RECEIVED. SYNs beyond are
+/// dropped, so a SYN flood can't grow the connection table without bound.
+static MAX_HALF_OPEN: usize = 64;
 
 pub trait Service {
+    /// Tells the service which connection it serves, right before
+    /// `on_connect`
+    fn on_open(&mut self, _quad: Quad) {}
+
     fn on_connect(&mut self, data: &[u8]) -> Response;
     fn on_receive(&mut self, data: &[u8]) -> Response;
     fn on_reset(&mut self);
     fn on_close(&mut self, data: &[u8]);
+
+    /// Text left the send buffer, so `TCP::send` takes more
+    fn on_sent(&mut self) {}
+
+    /// The peer has nothing more to send, and all it sent was received.
+    /// Closing our side as well is the default.
+    fn on_fin(&mut self) -> Response {
+        Response::Close(Vec::new())
+    }
+
+    /// Octets the service can take in `on_receive` right now. Text beyond
+    /// that waits in the receive buffer, which closes the advertised window.
+    fn receive_capacity(&self) -> usize {
+        usize::MAX
+    }
 }
 
 pub enum Response {
     None,
     Data(Vec<u8>),
+    /// Data that is sent right away with PSH set, even if Nagle's algorithm
+    /// would hold it back
+    Push(Vec<u8>),
     Close(Vec<u8>),
 }
 
-pub struct TCP {
-    pub iface: tun_tap::Iface,
-    buf: [u8; 2000],
-    window_size: u16,
-    pub tcb: TCB,
+#[allow(clippy::upper_case_acronyms)]
+pub struct TCP<D: Device> {
+    pub iface: D,
+    buf: Vec<u8>,
+    addr: [u8; 4],
+    listeners: HashMap<u16, Listener>,
+    connections: HashMap<Quad, TCB>,
+    next_ephemeral_port: u16,
+    isn_gen: Box<dyn IsnGenerator>,
+    recv_buffer_size: usize,
+    ack_delay: Duration,
+    drops: DropCounters,
+    /// Answer segments to unbound ports with ICMP port unreachable too
+    port_unreachable: bool,
+    /// Why the device failed to send, for good. The next `tick` returns it.
+    send_error: Option<io::Error>,
+}
+
+/// Packets dropped, by reason. Received packets are dropped before they
+/// reach a connection.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DropCounters {
+    /// Too short for the headers or the lengths they claim
+    pub malformed: u64,
+    /// Not TCP
+    pub not_tcp: u64,
+    pub ip_checksum: u64,
+    pub tcp_checksum: u64,
+    /// Destination address is not ours
+    pub wrong_address: u64,
+    /// Source or destination port is zero
+    pub wrong_port: u64,
+    /// SYNs to a listener with too many half-open connections already
+    pub half_open_full: u64,
+    /// Outgoing packets the device didn't take
+    pub send_failed: u64,
+}
+
+pub type Socket = ([u8; 4], u16);
+
+/// Identifies a connection by its local and foreign socket
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub struct Quad {
+    pub local: Socket,
+    pub foreign: Socket,
 }
 
-type Socket = ([u8; 4], u16);
+/// Creates a fresh service for every connection accepted by a listener
+pub type ServiceFactory = Box<dyn FnMut() -> Box<dyn Service>>;
 
+/// A port accepting connections
+struct Listener {
+    make_svc: ServiceFactory,
+    make_cc: CongestionFactory,
+    nodelay: bool,
+    keepalive: Option<Keepalive>,
+    max_half_open: usize,
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct TCB {
     pub state: TCPState,
     pub svc: Box<dyn Service>,
     local_socket: Socket,
     foreign_socket: Option<Socket>,
     open_mode: OpenMode,
-    irs: u32,
-    iss: u32,
-    snd_una: u32,
-    snd_nxt: u32,
+    irs: SeqNum,
+    iss: SeqNum,
+    snd_una: SeqNum,
+    snd_nxt: SeqNum,
     snd_wnd: u32,
-    snd_wl1: u32,
-    snd_wl2: u32,
-    rcv_nxt: u32,
+    snd_wl1: SeqNum,
+    snd_wl2: SeqNum,
+    rcv_nxt: SeqNum,
+    /// Right edge of the receive window we last advertised
+    rcv_adv: SeqNum,
+    /// Received text not yet taken by the service
+    rcv_buf: VecDeque<u8>,
+    /// The peer's FIN arrived while text was still buffered, so the service
+    /// hasn't heard of it yet
+    fin_undelivered: bool,
+    rcv_buf_size: usize,
+    /// When received text was first left unacknowledged, while an ACK is
+    /// being delayed
+    ack_pending: Option<Instant>,
+    /// Full-sized segments received since we last sent an ACK
+    unacked_segments: u32,
+    ack_delay: Duration,
+    /// Text handed over by the service but not sent yet
+    snd_buf: VecDeque<u8>,
+    /// Our FIN is queued behind the send buffer and not sent yet
+    fin_pending: bool,
+    /// Octets at the front of the send buffer that were pushed
+    snd_push: usize,
+    /// Nagle's algorithm is off
+    nodelay: bool,
+    /// Largest segment text the peer accepts
+    snd_mss: usize,
+    /// Largest segment text we accept, as told to the peer
+    rcv_mss: usize,
+    /// Window scaling is offered, or agreed on once synchronized
+    window_scaling: bool,
+    /// Shift of the windows the peer advertises
+    snd_wscale: u8,
+    /// Shift of the windows we advertise
+    rcv_wscale: u8,
+    /// SACK is offered, or agreed on once synchronized
+    sack_permitted: bool,
+    /// Timestamps are offered, or agreed on once synchronized
+    timestamps: bool,
+    /// Latest TSval to echo to the peer
+    ts_recent: u32,
+    /// Our timestamp clock counts milliseconds from here
+    ts_base: Instant,
+    cc: Box<dyn CongestionControl>,
+    /// Duplicate ACKs in a row
+    dup_acks: u32,
+    /// Added to the congestion window for segments that left the network
+    /// during fast recovery without SACK (RFC 6582)
+    cwnd_inflation: u32,
     retransmission_queue: Vec<Segment>,
+    /// SND.NXT when fast recovery started, while it is going on
+    recovery_point: Option<SeqNum>,
+    /// The recovery going on follows a retransmission timeout rather than
+    /// duplicate ACKs, so the congestion window grows by slow start
+    rto_recovery: bool,
+    /// SND.NXT when the retransmission timer last expired. Duplicate ACKs
+    /// for data sent before don't start fast retransmit (RFC 6582 4.1).
+    timeout_point: Option<SeqNum>,
+    reassembly: Reassembly,
     timer_pending: Option<Timer>,
+    rtt: RttEstimator,
+    /// Retransmissions since something new was acked
+    retransmissions: u32,
+    /// Window probes sent since the send window closed
+    probes: u32,
+    keepalive: Option<Keepalive>,
+    /// When the latest segment from the peer arrived
+    last_received: Instant,
+    /// Keepalive probes sent since then
+    keepalive_probes: u32,
 }
 
 #[derive(Eq, PartialEq)]
@@ -56,12 +227,58 @@ pub enum OpenMode {
 
 enum Timer {
     Retransmission(Instant),
+    /// Probes a zero send window, so a lost window update can't stall the
+    /// connection (RFC 9293 3.8.6.1)
+    Persist(Instant),
     TimeWait(Instant),
 }
 
-type Segment = (Ipv4Header, TcpHeader, Vec<u8>);
+/// When to check that the peer of an idle connection is still there
+/// (RFC 1122 4.2.3.6)
+#[derive(Clone, Copy, Debug)]
+pub struct Keepalive {
+    /// Time without any segment from the peer before the first probe
+    pub idle: Duration,
+    /// Time between unanswered probes
+    pub interval: Duration,
+    /// Unanswered probes after which the connection is aborted
+    pub count: u32,
+}
 
-#[derive(Eq, PartialEq, Debug)]
+impl Default for Keepalive {
+    fn default() -> Self {
+        Keepalive {
+            // No less than two hours, says RFC 1122
+            idle: Duration::from_secs(2 * 60 * 60),
+            interval: Duration::from_secs(75),
+            count: 9,
+        }
+    }
+}
+
+/// A sent segment that is kept until acknowledged
+#[derive(Clone)]
+struct Segment {
+    tcph: TcpHeader,
+    data: Vec<u8>,
+    sent_at: Instant,
+    retransmitted: bool,
+    /// The peer holds this segment, as told by a SACK block
+    sacked: bool,
+    /// Retransmitted since the current loss recovery started
+    recovery_rexmit: bool,
+    /// Deemed lost when the retransmission timer expired (RFC 6675 5.1)
+    lost: bool,
+}
+
+impl Segment {
+    /// Sequence number right after this segment
+    fn end(&self) -> SeqNum {
+        SeqNum(self.tcph.sequence_number) + seg_len(&self.tcph, &self.data)
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub enum TCPState {
     Closed,
     Listen,
@@ -76,112 +293,1191 @@ pub enum TCPState {
     TimeWait,
 }
 
-impl TCP {
-    pub fn with_iface(
-        iface: tun_tap::Iface,
+impl TCB {
+    fn new(
         svc: Box<dyn Service>,
+        cc: Box<dyn CongestionControl>,
         local_socket: Socket,
         foreign_socket: Option<Socket>,
         open_mode: OpenMode,
+        rcv_buf_size: usize,
+        ack_delay: Duration,
     ) -> Self {
-        Self {
-            iface,
-            buf: [0u8; 2000],
-            window_size: 1000,
-            tcb: TCB {
-                state: TCPState::Listen,
-                svc,
-                local_socket,
-                foreign_socket,
-                open_mode,
-                irs: 0,
-                iss: 0,
-                snd_una: 0,
-                snd_nxt: 0,
-                snd_wl1: 0,
-                snd_wl2: 0,
-                snd_wnd: 0,
-                rcv_nxt: 0,
-                retransmission_queue: Vec::new(),
-                timer_pending: None,
+        TCB {
+            state: TCPState::Listen,
+            svc,
+            local_socket,
+            foreign_socket,
+            open_mode,
+            irs: SeqNum(0),
+            iss: SeqNum(0),
+            snd_una: SeqNum(0),
+            snd_nxt: SeqNum(0),
+            snd_wl1: SeqNum(0),
+            snd_wl2: SeqNum(0),
+            snd_wnd: 0,
+            rcv_nxt: SeqNum(0),
+            rcv_adv: SeqNum(0),
+            rcv_buf: VecDeque::new(),
+            fin_undelivered: false,
+            rcv_buf_size,
+            ack_pending: None,
+            unacked_segments: 0,
+            ack_delay,
+            snd_buf: VecDeque::new(),
+            fin_pending: false,
+            snd_push: 0,
+            nodelay: false,
+            snd_mss: DEFAULT_MSS,
+            rcv_mss: DEFAULT_MSS,
+            window_scaling: true,
+            snd_wscale: 0,
+            // Smallest shift that lets the whole buffer be advertised
+            rcv_wscale: (0..MAX_WINDOW_SCALE)
+                .find(|shift| rcv_buf_size <= (u16::MAX as usize) << shift)
+                .unwrap_or(MAX_WINDOW_SCALE),
+            sack_permitted: true,
+            timestamps: true,
+            ts_recent: 0,
+            ts_base: Instant::now(),
+            cc,
+            dup_acks: 0,
+            cwnd_inflation: 0,
+            retransmission_queue: Vec::new(),
+            recovery_point: None,
+            rto_recovery: false,
+            timeout_point: None,
+            reassembly: Reassembly::new(),
+            timer_pending: None,
+            rtt: RttEstimator::new(),
+            retransmissions: 0,
+            probes: 0,
+            keepalive: None,
+            last_received: Instant::now(),
+            keepalive_probes: 0,
+        }
+    }
+
+    /// Local and foreign socket, once the foreign one is known
+    fn quad(&self) -> Quad {
+        Quad {
+            local: self.local_socket,
+            foreign: self.foreign_socket.expect("Foreign socket not known"),
+        }
+    }
+
+    /// Turns Nagle's algorithm off for this connection, or back on
+    fn set_nodelay(&mut self, nodelay: bool) {
+        self.nodelay = nodelay;
+    }
+
+    /// Sends keepalives on this connection, or stops with `None`
+    fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        self.keepalive = keepalive;
+    }
+
+    /// Nagle's algorithm (RFC 896, RFC 1122 4.2.3.4): a segment shorter
+    /// than the MSS waits until all data sent is acknowledged, unless the
+    /// text in it was pushed
+    fn nagle_allows(&self) -> bool {
+        self.nodelay || self.snd_nxt == self.snd_una || self.snd_push > 0
+    }
+
+    /// RCV.WND, what is left of the window we last advertised
+    fn rcv_wnd(&self) -> u32 {
+        if self.rcv_adv > self.rcv_nxt {
+            self.rcv_adv - self.rcv_nxt
+        } else {
+            0
+        }
+    }
+
+    /// Free space in the receive buffer, as far as a window can express it
+    fn rcv_buf_free(&self) -> u32 {
+        self.rcv_buf_size
+            .saturating_sub(self.rcv_buf.len())
+            .min((u16::MAX as usize) << self.rcv_wscale) as u32
+    }
+
+    /// Whether opening the window to the free buffer space is worth it.
+    ///
+    /// Receiver side silly window syndrome avoidance (RFC 1122 4.2.3.3): the
+    /// right edge only moves once it can move by min(buffer / 2, MSS).
+    fn window_update_due(&self) -> bool {
+        self.rcv_buf_free() >= self.rcv_wnd() + self.sws_threshold()
+    }
+
+    /// Least the right edge of the receive window moves by
+    fn sws_threshold(&self) -> u32 {
+        (self.rcv_buf_size / 2).min(self.rcv_mss) as u32
+    }
+
+    /// Whether a window update may ride on the delayed ACK, as the peer
+    /// has enough window left not to stall until then
+    fn window_update_can_wait(&self) -> bool {
+        self.ack_pending.is_some() && self.rcv_wnd() >= self.sws_threshold()
+    }
+
+    /// Window to put in an outgoing segment. The window never shrinks.
+    fn advertise_window(&mut self) -> u16 {
+        if self.window_update_due() {
+            self.rcv_adv = self.rcv_nxt + self.rcv_buf_free();
+        }
+        (self.rcv_wnd() >> self.rcv_wscale) as u16
+    }
+
+    /// Window to put in a SYN, which is never scaled
+    fn syn_window(&mut self) -> u16 {
+        let window = self.rcv_buf_free().min(u16::MAX as u32);
+        self.rcv_adv = self.rcv_nxt + window;
+        window as u16
+    }
+
+    /// Options to put in a SYN: all we offer, or once the peer's SYN is in,
+    /// what both sides agreed on
+    fn syn_options(&self) -> Options {
+        Options {
+            mss: Some(self.rcv_mss.min(u16::MAX as usize) as u16),
+            window_scale: if self.window_scaling {
+                Some(self.rcv_wscale)
+            } else {
+                None
             },
+            sack_permitted: self.sack_permitted,
+            timestamp: self.timestamp_option(),
+            sack: Vec::new(),
         }
     }
 
-    pub fn tick(&mut self) {
-        match self.tcb.timer_pending {
-            Some(Timer::Retransmission(start_time)) => {
-                if self.tcb.retransmission_queue.len() > 0
-                    && Instant::now() - start_time > TIMEOUT_RETR
-                {
-                    let (iph, mut tcph, pld) =
-                        self.tcb.retransmission_queue.first().unwrap().clone();
-                    self.send_tcph(&mut tcph, &iph, &pld);
-                }
+    /// Settles the options of the connection from what the peer's SYN offers
+    fn negotiate(&mut self, peer: &Options) {
+        // Our own segments must fit the device too, even one whose MTU is
+        // below what MIN_MSS needs
+        self.snd_mss = peer
+            .mss
+            .map_or(DEFAULT_MSS, usize::from)
+            .max(MIN_MSS)
+            .min(self.rcv_mss);
+
+        // Window scaling only applies if both sides send the option
+        match peer.window_scale {
+            Some(shift) if self.window_scaling => self.snd_wscale = shift,
+            _ => {
+                self.window_scaling = false;
+                self.snd_wscale = 0;
+                self.rcv_wscale = 0;
             }
-            Some(Timer::TimeWait(start_time)) => {
-                if Instant::now() - start_time > TIMEOUT_2MSL {
-                    self.tcb.state = TCPState::Closed;
-                    self.tcb.svc.on_close(&[]);
+        }
+
+        self.sack_permitted &= peer.sack_permitted;
+
+        self.timestamps &= peer.timestamp.is_some();
+        if let Some((tsval, _)) = peer.timestamp {
+            self.ts_recent = tsval;
+        }
+
+        self.cc.init(self.snd_mss as u32);
+    }
+
+    /// Current value of our timestamp clock
+    fn tsval(&self) -> u32 {
+        self.ts_base.elapsed().as_millis() as u32
+    }
+
+    /// Timestamp option for a segment sent now, if timestamps are in use
+    fn timestamp_option(&self) -> Option<(u32, u32)> {
+        if self.timestamps {
+            Some((self.tsval(), self.ts_recent))
+        } else {
+            None
+        }
+    }
+
+    /// Puts a fresh timestamp option into `tcph`, if timestamps are in use
+    fn write_timestamp(&self, tcph: &mut TcpHeader) {
+        let options = Options {
+            timestamp: self.timestamp_option(),
+            ..Options::default()
+        };
+        options.write(tcph);
+    }
+
+    /// SACK blocks telling the peer which text we hold beyond RCV.NXT
+    fn sack_blocks(&self) -> Vec<(u32, u32)> {
+        if !self.sack_permitted {
+            return Vec::new();
+        }
+        self.reassembly
+            .sack_blocks()
+            .into_iter()
+            .map(|(left, right)| (left.0, right.0))
+            .collect()
+    }
+
+    /// Header of a new segment on this connection, advertising the window
+    fn tcph_new(&mut self, seq_num: SeqNum) -> TcpHeader {
+        let mut tcph = self.tcph_unwindowed(seq_num);
+        tcph.window_size = self.advertise_window();
+        tcph
+    }
+
+    /// Header of a new segment on this connection, with its options but
+    /// without a window yet
+    fn tcph_unwindowed(&self, seq_num: SeqNum) -> TcpHeader {
+        let mut tcph = TcpHeader::new(
+            self.local_socket.1,            // Source port
+            self.foreign_socket.unwrap().1, // Destination port
+            seq_num.0,                      // Sequence Number.
+            0,                              // Window size.
+        );
+        let options = Options {
+            timestamp: self.timestamp_option(),
+            sack: self.sack_blocks(),
+            ..Options::default()
+        };
+        options.write(&mut tcph);
+        tcph
+    }
+
+    /// Octets that can be sent right now without overrunning the peer's
+    /// window or the congestion window
+    fn usable_window(&self) -> u32 {
+        let outstanding = self.snd_nxt - self.snd_una;
+        // In loss recovery the pipe estimates what is really in flight
+        let in_flight = if self.recovery_point.is_some() {
+            self.pipe()
+        } else {
+            outstanding
+        };
+        self.snd_wnd
+            .saturating_sub(outstanding)
+            .min(self.cwnd().saturating_sub(in_flight))
+    }
+
+    fn cwnd(&self) -> u32 {
+        self.cc.cwnd() + self.cwnd_inflation + self.limited_transmit()
+    }
+
+    /// Limited transmit (RFC 3042): each of the first two duplicate ACKs
+    /// lets one new segment out beyond the congestion window, so that a
+    /// small window still yields enough duplicate ACKs for fast retransmit
+    fn limited_transmit(&self) -> u32 {
+        if self.recovery_point.is_some() {
+            return 0;
+        }
+        self.dup_acks.min(DUP_THRESH as u32 - 1) * self.snd_mss as u32
+    }
+
+    /// Whether the first unacknowledged segment is deemed lost, from three
+    /// duplicate ACKs or from SACKs (RFC 6675 5)
+    fn loss_detected(&self) -> bool {
+        if self.retransmission_queue.is_empty() {
+            return false;
+        }
+        let dup_acks = self.dup_acks >= DUP_THRESH as u32
+            && self
+                .timeout_point
+                .is_none_or(|timeout_point| self.snd_una > timeout_point);
+        dup_acks || self.sack_permitted && self.is_lost(0)
+    }
+
+    /// Marks queued segments that lie within the SACK blocks of an ACK
+    fn update_scoreboard(&mut self, blocks: &[(u32, u32)]) {
+        for &(left, right) in blocks {
+            let (left, right) = (SeqNum(left), SeqNum(right));
+            // Blocks outside of what is in flight are bogus
+            if left < self.snd_una || right > self.snd_nxt || right <= left {
+                continue;
+            }
+            for seg in &mut self.retransmission_queue {
+                if left <= SeqNum(seg.tcph.sequence_number) && seg.end() <= right {
+                    seg.sacked = true;
                 }
             }
-            None => {}
         }
+    }
 
-        let read = self.iface.recv(&mut self.buf).expect("Failed to read");
-        let data = self.buf.clone();
-        let (in_iph, in_ippld) =
-            if let Ok((in_iph, pld)) = Ipv4Header::read_from_slice(&data[0..read]) {
-                (in_iph, pld)
+    /// IsLost() of RFC 6675: whether enough was SACKed above the segment at
+    /// `index` in the retransmission queue to deem it lost, or a timeout did
+    fn is_lost(&self, index: usize) -> bool {
+        if self.retransmission_queue[index].lost {
+            return true;
+        }
+        let above = self.retransmission_queue[index + 1..]
+            .iter()
+            .filter(|seg| seg.sacked);
+        let (count, octets) = above.fold((0, 0), |(count, octets), seg| {
+            (count + 1, octets + seg.data.len())
+        });
+        count >= DUP_THRESH || octets > (DUP_THRESH - 1) * self.snd_mss
+    }
+
+    /// The pipe of RFC 6675: octets estimated to be in flight
+    fn pipe(&self) -> u32 {
+        let mut pipe = 0;
+        for (index, seg) in self.retransmission_queue.iter().enumerate() {
+            if seg.sacked {
+                continue;
+            }
+            let len = seg_len(&seg.tcph, &seg.data);
+            if !self.is_lost(index) {
+                pipe += len;
+            }
+            if seg.recovery_rexmit {
+                pipe += len;
+            }
+        }
+        pipe
+    }
+
+    /// NextSeg() of RFC 6675, as far as retransmissions go: the first lost
+    /// segment not retransmitted in this recovery yet
+    fn next_lost_segment(&self) -> Option<usize> {
+        (0..self.retransmission_queue.len()).find(|&index| {
+            let seg = &self.retransmission_queue[index];
+            !seg.sacked && !seg.recovery_rexmit && self.is_lost(index)
+        })
+    }
+
+    /// Whether `seg_ack` acknowledges our FIN
+    fn fin_acked(&self, seg_ack: SeqNum) -> bool {
+        // FIN is the last octet we sent, once it is sent at all
+        !self.fin_pending && seg_ack == self.snd_nxt
+    }
+
+    /// When the pending timer of this connection expires
+    fn timer_deadline(&self) -> Option<Instant> {
+        match self.timer_pending {
+            Some(Timer::Retransmission(start_time)) if !self.retransmission_queue.is_empty() => {
+                Some(start_time + self.rtt.rto())
+            }
+            Some(Timer::Persist(start_time)) => {
+                Some(start_time + self.rtt.persist_timeout(self.probes))
+            }
+            Some(Timer::TimeWait(start_time)) => Some(start_time + TIMEOUT_2MSL),
+            _ => None,
+        }
+    }
+
+    /// When the next keepalive probe is due, if the connection is idle:
+    /// synchronized, and no other timer waits for an answer from the peer
+    fn keepalive_deadline(&self) -> Option<Instant> {
+        let keepalive = self.keepalive?;
+        if self.timer_deadline().is_some()
+            || ![TCPState::Estab, TCPState::FinWait2, TCPState::CloseWait].contains(&self.state)
+        {
+            return None;
+        }
+        Some(self.last_received + keepalive.idle + keepalive.interval * self.keepalive_probes)
+    }
+
+    /// When the delayed ACK must go out
+    fn ack_deadline(&self) -> Option<Instant> {
+        self.ack_pending
+            .map(|start_time| start_time + self.ack_delay)
+    }
+
+    /// Starts the persist timer when the peer's zero window holds back
+    /// buffered text and no retransmission will elicit a window update, or
+    /// stops it once the window opens
+    fn update_persist_timer(&mut self) {
+        let persisting = matches!(self.timer_pending, Some(Timer::Persist(_)));
+        if self.snd_wnd > 0 {
+            self.probes = 0;
+            if persisting {
+                // Unacknowledged probes fall to the retransmission timer
+                self.timer_pending = if self.retransmission_queue.is_empty() {
+                    None
+                } else {
+                    Some(Timer::Retransmission(Instant::now()))
+                };
+            }
+        } else if !persisting && self.retransmission_queue.is_empty() && !self.snd_buf.is_empty() {
+            self.timer_pending = Some(Timer::Persist(Instant::now()));
+        }
+    }
+
+    /// Removes segments that are fully acknowledged from the retransmission
+    /// queue, taking an RTT measurement from the last of them
+    fn remove_acked_segments(&mut self) {
+        let snd_una = self.snd_una;
+        let mut last_sent_at = None;
+        let mut retransmitted = false;
+        self.retransmission_queue.retain(|seg| {
+            if seg.end() <= snd_una {
+                last_sent_at = Some(seg.sent_at);
+                retransmitted |= seg.retransmitted;
+                false
             } else {
-                // eprintln!("Dropping packet: not IPv4");
-                return;
+                true
+            }
+        });
+
+        if let Some(sent_at) = last_sent_at {
+            self.retransmissions = 0;
+            // Restart the timer for the rest of the queue, or stop it if all
+            // was acked (RFC 6298 5.2, 5.3)
+            if self.retransmission_queue.is_empty() {
+                self.timer_pending = None;
+            } else {
+                self.timer_pending = Some(Timer::Retransmission(Instant::now()));
+            }
+            // Karn's algorithm: the ACK of a retransmitted segment may be for
+            // any of its transmissions, so it can't be timed. Neither can the
+            // segments it covers along with one, whose ACK waited for the
+            // retransmission.
+            if !retransmitted {
+                self.rtt.sample(sent_at.elapsed());
+            }
+        }
+    }
+}
+
+impl<D: Device> TCP<D> {
+    /// Creates a TCP on `iface` which owns the IP address `addr`
+    pub fn with_iface(iface: D, addr: [u8; 4]) -> Self {
+        let mtu = iface.mtu();
+        Self {
+            iface,
+            buf: vec![0u8; mtu],
+            addr,
+            listeners: HashMap::new(),
+            connections: HashMap::new(),
+            next_ephemeral_port: EPHEMERAL_PORT_START,
+            isn_gen: Box::new(Rfc6528Isn::new()),
+            recv_buffer_size: RECV_BUFFER_SIZE,
+            ack_delay: ACK_DELAY,
+            drops: DropCounters::default(),
+            port_unreachable: false,
+            send_error: None,
+        }
+    }
+
+    /// Received packets dropped so far as invalid or not for us
+    pub fn drops(&self) -> DropCounters {
+        self.drops
+    }
+
+    /// Sends an ICMP port unreachable besides the RST when a segment comes
+    /// for a port with neither a listener nor a connection. Off by default.
+    pub fn set_port_unreachable(&mut self, enabled: bool) {
+        self.port_unreachable = enabled;
+    }
+
+    /// Sets the receive buffer size of connections opened from now on
+    pub fn set_recv_buffer_size(&mut self, size: usize) {
+        self.recv_buffer_size = size;
+    }
+
+    /// Sets how long connections opened from now on hold back an ACK for
+    /// received text, at most 500 ms. Zero acknowledges every segment.
+    pub fn set_ack_delay(&mut self, delay: Duration) {
+        self.ack_delay = delay.min(MAX_ACK_DELAY);
+    }
+
+    /// Replaces the default RFC 6528 ISN generator, eg. with a deterministic
+    /// one for testing
+    pub fn set_isn_generator(&mut self, isn_gen: Box<dyn IsnGenerator>) {
+        self.isn_gen = isn_gen;
+    }
+
+    /// Accepts connections on `port`, serving each one with a new service
+    /// from `make_svc`
+    pub fn listen(&mut self, port: u16, make_svc: ServiceFactory) {
+        let listener = Listener {
+            make_svc,
+            make_cc: Box::new(|| Box::new(NewReno::new())),
+            nodelay: false,
+            keepalive: None,
+            max_half_open: MAX_HALF_OPEN,
+        };
+        self.listeners.insert(port, listener);
+    }
+
+    /// Picks the congestion control of connections accepted on `port` from
+    /// now on. NewReno is used unless told otherwise.
+    pub fn set_congestion_control(&mut self, port: u16, make_cc: CongestionFactory) {
+        if let Some(listener) = self.listeners.get_mut(&port) {
+            listener.make_cc = make_cc;
+        }
+    }
+
+    /// Turns Nagle's algorithm off for connections accepted on `port` from
+    /// now on, or back on
+    pub fn set_nodelay(&mut self, port: u16, nodelay: bool) {
+        if let Some(listener) = self.listeners.get_mut(&port) {
+            listener.nodelay = nodelay;
+        }
+    }
+
+    /// Sends keepalives on connections accepted on `port` from now on, or
+    /// stops with `None`. Keepalives are off unless turned on.
+    pub fn set_keepalive(&mut self, port: u16, keepalive: Option<Keepalive>) {
+        if let Some(listener) = self.listeners.get_mut(&port) {
+            listener.keepalive = keepalive;
+        }
+    }
+
+    /// Sets how many connections on `port` may be half-open, waiting for the
+    /// ACK of our SYN. SYNs beyond are dropped.
+    pub fn set_max_half_open(&mut self, port: u16, max_half_open: usize) {
+        if let Some(listener) = self.listeners.get_mut(&port) {
+            listener.max_half_open = max_half_open;
+        }
+    }
+
+    /// Stops accepting connections on `port`. Connections accepted already
+    /// go on.
+    pub fn unlisten(&mut self, port: u16) {
+        self.listeners.remove(&port);
+    }
+
+    /// Whether connections are accepted on `port`
+    pub fn is_listening(&self, port: u16) -> bool {
+        self.listeners.contains_key(&port)
+    }
+
+    /// State of the connection `quad`, if it exists
+    pub fn state(&self, quad: &Quad) -> Option<TCPState> {
+        self.connections.get(quad).map(|tcb| tcb.state)
+    }
+
+    /// Turns Nagle's algorithm off for the connection `quad`, or back on
+    pub fn set_connection_nodelay(&mut self, quad: &Quad, nodelay: bool) -> io::Result<()> {
+        let tcb = self
+            .connections
+            .get_mut(quad)
+            .ok_or(ErrorKind::NotConnected)?;
+        tcb.set_nodelay(nodelay);
+        Ok(())
+    }
+
+    /// Sends keepalives on the connection `quad`, or stops with `None`
+    pub fn set_connection_keepalive(
+        &mut self,
+        quad: &Quad,
+        keepalive: Option<Keepalive>,
+    ) -> io::Result<()> {
+        let tcb = self
+            .connections
+            .get_mut(quad)
+            .ok_or(ErrorKind::NotConnected)?;
+        tcb.set_keepalive(keepalive);
+        Ok(())
+    }
+
+    /// Queues as much of `data` as the send buffer takes on the connection
+    /// `quad`, and sends what the windows allow
+    ///
+    /// Returns the octets taken, which are none while the buffer is full.
+    /// Fails once our side of the connection is closed.
+    pub fn send(&mut self, quad: &Quad, data: &[u8]) -> io::Result<usize> {
+        self.with_connection(quad, |tcp, tcb| {
+            match tcb.state {
+                TCPState::SynSent | TCPState::SynRecvd => {
+                    return Err(ErrorKind::NotConnected.into())
+                }
+                TCPState::Estab | TCPState::CloseWait if !tcb.fin_pending => {}
+                _ => return Err(ErrorKind::BrokenPipe.into()),
+            }
+            let len = SEND_BUFFER_SIZE
+                .saturating_sub(tcb.snd_buf.len())
+                .min(data.len());
+            tcb.snd_buf.extend(&data[..len]);
+            tcp.send_pending(tcb);
+            Ok(len)
+        })
+    }
+
+    /// Closes our side of the connection `quad` once the send buffer is
+    /// sent. The peer may go on sending.
+    pub fn shutdown(&mut self, quad: &Quad) -> io::Result<()> {
+        self.with_connection(quad, |tcp, tcb| {
+            if [TCPState::Estab, TCPState::CloseWait].contains(&tcb.state) && !tcb.fin_pending {
+                tcp.close(tcb);
+            }
+            Ok(())
+        })
+    }
+
+    /// Resets the connection `quad`
+    pub fn reset(&mut self, quad: &Quad) -> io::Result<()> {
+        self.with_connection(quad, |tcp, tcb| {
+            tcp.abort(tcb);
+            Ok(())
+        })
+    }
+
+    /// Hands text held back for `quad` to its service right away, eg. once
+    /// a socket was read from, and opens the window if that is due
+    pub fn drain(&mut self, quad: &Quad) -> io::Result<()> {
+        self.with_connection(quad, |tcp, tcb| {
+            tcp.on_drain(tcb);
+            Ok(())
+        })
+    }
+
+    /// Runs `f` on the connection `quad`, which is forgotten if it ends up
+    /// closed
+    fn with_connection<T>(
+        &mut self,
+        quad: &Quad,
+        f: impl FnOnce(&mut Self, &mut TCB) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut tcb = self
+            .connections
+            .remove(quad)
+            .ok_or(ErrorKind::NotConnected)?;
+        let result = f(self, &mut tcb);
+        if tcb.state != TCPState::Closed {
+            self.connections.insert(*quad, tcb);
+        }
+        result
+    }
+
+    /// Actively opens a connection to `remote`, served by `svc` once it is
+    /// established
+    pub fn connect(&mut self, remote: Socket, svc: Box<dyn Service>) -> Quad {
+        let quad = Quad {
+            local: (self.addr, self.ephemeral_port(remote)),
+            foreign: remote,
+        };
+
+        let mut tcb = TCB::new(
+            svc,
+            Box::new(NewReno::new()),
+            quad.local,
+            Some(quad.foreign),
+            OpenMode::Active,
+            self.recv_buffer_size,
+            self.ack_delay,
+        );
+        tcb.iss = self.isn_gen.isn(&quad);
+        tcb.snd_una = tcb.iss;
+        tcb.rcv_mss = self.mss();
+        tcb.state = TCPState::SynSent;
+
+        let mut out_tcph = tcb.tcph_new(tcb.iss);
+        out_tcph.window_size = tcb.syn_window();
+        tcb.syn_options().write(&mut out_tcph);
+        out_tcph.syn = true;
+        self.send_reliable(&mut tcb, out_tcph, Vec::new());
+
+        self.connections.insert(quad, tcb);
+        quad
+    }
+
+    /// MSS we can receive, given the MTU of the device. At least an octet,
+    /// so the window arithmetic holds for an MTU that leaves no room.
+    fn mss(&self) -> usize {
+        self.iface.mtu().saturating_sub(HEADERS_LEN).max(1)
+    }
+
+    /// Picks a local port not used by any listener or connection to `remote`
+    fn ephemeral_port(&mut self, remote: Socket) -> u16 {
+        loop {
+            let port = self.next_ephemeral_port;
+            self.next_ephemeral_port = self
+                .next_ephemeral_port
+                .checked_add(1)
+                .unwrap_or(EPHEMERAL_PORT_START);
+
+            let quad = Quad {
+                local: (self.addr, port),
+                foreign: remote,
             };
+            if !self.listeners.contains_key(&port) && !self.connections.contains_key(&quad) {
+                return port;
+            }
+        }
+    }
 
-        if in_iph.protocol != 6 {
-            // eprintln!("Dropping packet: not TCP");
-            return;
+    /// Runs one iteration of the event loop: waits until a packet arrives or
+    /// the earliest timer expires, then processes everything that is due
+    ///
+    /// Fails only if the device does.
+    pub fn tick(&mut self) -> Result<()> {
+        let timeout = self
+            .poll_at()
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        self.process(timeout)
+    }
+
+    /// Processes whatever packets and timers are due without waiting, for
+    /// callers that wait for the device and `poll_at` themselves
+    pub fn poll(&mut self) -> Result<()> {
+        self.process(Some(Duration::from_secs(0)))
+    }
+
+    /// Waits up to `timeout` for a packet, then processes everything due
+    fn process(&mut self, timeout: Option<Duration>) -> Result<()> {
+        let mut readable = self.iface.poll(timeout)?;
+        while readable {
+            self.receive()?;
+            readable = self.iface.poll(Some(Duration::from_secs(0)))?;
         }
 
-        let (in_tcph, in_tcppld) =
-            TcpHeader::read_from_slice(in_ippld).expect("Failed to parse TCP");
+        let mut connections = std::mem::take(&mut self.connections);
+        for tcb in connections.values_mut() {
+            self.on_drain(tcb);
+            self.on_timer(tcb);
+            self.on_ack_timer(tcb);
+            self.on_keepalive_timer(tcb);
+        }
+        connections.retain(|_, tcb| tcb.state != TCPState::Closed);
+        self.connections = connections;
+
+        match self.send_error.take() {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
 
-        let seg_len = {
-            let mut seg_len = in_tcppld.len() as u32;
-            if in_tcph.syn {
-                seg_len += 1;
+    /// Earliest time at which a timer of some connection expires, or now if
+    /// a service can take buffered text
+    pub fn poll_at(&self) -> Option<Instant> {
+        if self
+            .connections
+            .values()
+            .any(|tcb| !tcb.rcv_buf.is_empty() && tcb.svc.receive_capacity() > 0)
+        {
+            return Some(Instant::now());
+        }
+        self.connections
+            .values()
+            .flat_map(|tcb| {
+                tcb.timer_deadline()
+                    .into_iter()
+                    .chain(tcb.ack_deadline())
+                    .chain(tcb.keepalive_deadline())
+            })
+            .min()
+    }
+
+    /// Reads one packet from the device and processes it
+    fn receive(&mut self) -> Result<()> {
+        let read = match self.iface.recv(&mut self.buf) {
+            Ok(read) => read,
+            // Nothing to read from a non-blocking device
+            Err(e) if [ErrorKind::WouldBlock, ErrorKind::Interrupted].contains(&e.kind()) => {
+                return Ok(())
             }
-            if in_tcph.fin {
-                seg_len += 1;
+            Err(e) => return Err(e.into()),
+        };
+        let data = self.buf.clone();
+        let (in_iph, in_tcph, in_tcppld) = match self.validate(&data[0..read]) {
+            Some(packet) => packet,
+            None => return Ok(()),
+        };
+
+        let quad = Quad {
+            local: (in_iph.destination, in_tcph.destination_port),
+            foreign: (in_iph.source, in_tcph.source_port),
+        };
+
+        if let Some(tcb) = self.connections.get(&quad) {
+            // A new SYN beyond the old connection's sequence space may reopen
+            // a connection in TIME-WAIT (RFC 1122 4.2.2.13)
+            if tcb.state == TCPState::TimeWait
+                && in_tcph.syn
+                && !in_tcph.ack
+                && SeqNum(in_tcph.sequence_number) > tcb.rcv_nxt
+            {
+                let mut tcb = self.connections.remove(&quad).unwrap();
+                tcb.svc.on_close(&[]);
+            }
+        }
+
+        let mut tcb = if let Some(tcb) = self.connections.remove(&quad) {
+            tcb
+        } else if let Some(listener) = self.listeners.get(&in_tcph.destination_port) {
+            // Only a SYN opens a connection. The rest is answered as in
+            // LISTEN, without a TCB.
+            if in_tcph.rst {
+                return Ok(());
+            }
+            if in_tcph.ack {
+                self.reset_simple(&in_tcph, &in_iph);
+                return Ok(());
+            }
+            if !in_tcph.syn {
+                return Ok(());
+            }
+
+            let half_open = self
+                .connections
+                .iter()
+                .filter(|(quad, tcb)| {
+                    quad.local.1 == in_tcph.destination_port && tcb.state == TCPState::SynRecvd
+                })
+                .count();
+            if half_open >= listener.max_half_open {
+                self.drops.half_open_full += 1;
+                return Ok(());
             }
-            seg_len
+
+            // Fresh TCB in LISTEN state, which turns into a connection on the SYN
+            let listener = self.listeners.get_mut(&in_tcph.destination_port).unwrap();
+            let mut tcb = TCB::new(
+                (listener.make_svc)(),
+                (listener.make_cc)(),
+                quad.local,
+                None,
+                OpenMode::Passive,
+                self.recv_buffer_size,
+                self.ack_delay,
+            );
+            tcb.nodelay = listener.nodelay;
+            tcb.keepalive = listener.keepalive;
+            tcb
+        } else {
+            // Nothing is bound to the port
+            self.closed_segment_arrives(&in_iph, &in_tcph, in_tcppld);
+            if self.port_unreachable && !in_tcph.rst {
+                let message = icmp::port_unreachable(&data[..read], in_iph.header_len());
+                self.send_icmp(in_iph.source, &message);
+            }
+            return Ok(());
         };
 
-        eprintln!("Received segment: ");
-        eprintln!("{:?}", in_tcppld);
-        eprintln!("Current State: {:?}", &self.tcb.state);
+        self.segment_arrives(&mut tcb, &in_iph, &in_tcph, in_tcppld);
 
-        match &self.tcb.state {
-            TCPState::Closed => {
-                if in_tcph.rst {
+        // A TCB that went back to LISTEN is just the listener again
+        if ![TCPState::Closed, TCPState::Listen].contains(&tcb.state) {
+            self.connections.insert(quad, tcb);
+        }
+        Ok(())
+    }
+
+    /// Parses a received IPv4 packet and checks that it is a TCP segment for
+    /// us, intact as far as its checksums tell. Anything else is counted
+    /// and dropped.
+    fn validate<'a>(&mut self, packet: &'a [u8]) -> Option<(Ipv4Header, TcpHeader, &'a [u8])> {
+        let (in_iph, rest) = match Ipv4Header::read_from_slice(packet) {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                self.drops.malformed += 1;
+                return None;
+            }
+        };
+        // The link may pad the packet beyond its total length
+        let in_ippld = match rest.get(..in_iph.payload_len as usize) {
+            Some(in_ippld) => in_ippld,
+            None => {
+                self.drops.malformed += 1;
+                return None;
+            }
+        };
+        if in_iph.protocol != IpTrafficClass::Tcp as u8 {
+            self.drops.not_tcp += 1;
+            return None;
+        }
+        let checksum_offload = self.iface.capabilities().checksum_offload;
+        if !checksum_offload && in_iph.calc_header_checksum().ok() != Some(in_iph.header_checksum) {
+            self.drops.ip_checksum += 1;
+            return None;
+        }
+        if in_iph.destination != self.addr {
+            self.drops.wrong_address += 1;
+            return None;
+        }
+
+        let (in_tcph, in_tcppld) = match TcpHeader::read_from_slice(in_ippld) {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                self.drops.malformed += 1;
+                return None;
+            }
+        };
+        if !checksum_offload
+            && in_tcph.calc_checksum_ipv4(&in_iph, in_tcppld).ok() != Some(in_tcph.checksum)
+        {
+            self.drops.tcp_checksum += 1;
+            return None;
+        }
+        if in_tcph.source_port == 0 || in_tcph.destination_port == 0 {
+            self.drops.wrong_port += 1;
+            return None;
+        }
+        Some((in_iph, in_tcph, in_tcppld))
+    }
+
+    /// Hands buffered text to a service that has room for it again, and
+    /// tells the peer once the window has opened far enough
+    fn on_drain(&mut self, tcb: &mut TCB) {
+        let sent = self.deliver(tcb);
+        if !sent
+            && [TCPState::Estab, TCPState::FinWait1, TCPState::FinWait2].contains(&tcb.state)
+            && tcb.window_update_due()
+            && !tcb.window_update_can_wait()
+        {
+            self.send_ack(tcb);
+        }
+    }
+
+    fn on_timer(&mut self, tcb: &mut TCB) {
+        match tcb.timer_deadline() {
+            Some(deadline) if Instant::now() >= deadline => {}
+            _ => return,
+        }
+
+        match tcb.timer_pending {
+            Some(Timer::Retransmission(_)) => {
+                if tcb.retransmissions >= MAX_RETRANSMISSIONS {
+                    self.abort(tcb);
                     return;
                 }
+                tcb.retransmissions += 1;
+                tcb.rtt.backoff();
+                tcb.cc
+                    .on_timeout(tcb.snd_nxt - tcb.snd_una, tcb.snd_mss as u32);
+                // A timeout ends fast recovery, the SACK scoreboard is kept
+                tcb.recovery_point = None;
+                tcb.timeout_point = Some(tcb.snd_nxt);
+                tcb.cwnd_inflation = 0;
+                tcb.dup_acks = 0;
+
+                if tcb.sack_permitted {
+                    // Whatever the peer doesn't hold is lost, and goes out
+                    // again as the window opens (RFC 6675 5.1)
+                    for seg in &mut tcb.retransmission_queue {
+                        seg.lost = !seg.sacked;
+                        seg.recovery_rexmit = false;
+                    }
+                    tcb.retransmission_queue[0].recovery_rexmit = true;
+                    tcb.recovery_point = Some(tcb.snd_nxt);
+                    tcb.rto_recovery = true;
+                }
+                self.retransmit(tcb, 0);
+                tcb.timer_pending = Some(Timer::Retransmission(Instant::now()));
+            }
+            Some(Timer::Persist(_)) => {
+                self.send_probe(tcb);
+                tcb.probes += 1;
+                // Probes go on for as long as the peer acknowledges them
+                // with a zero window (RFC 1122 4.2.2.17)
+                tcb.timer_pending = Some(Timer::Persist(Instant::now()));
+            }
+            Some(Timer::TimeWait(_)) => {
+                tcb.state = TCPState::Closed;
+                tcb.svc.on_close(&[]);
+            }
+            None => {}
+        }
+    }
 
-                if in_tcph.ack {
-                    self.reset_simple(&in_tcph, &in_iph);
-                    return;
+    /// Sends the ACK that was held back, once the delay is over
+    fn on_ack_timer(&mut self, tcb: &mut TCB) {
+        match tcb.ack_deadline() {
+            Some(deadline) if Instant::now() >= deadline => self.send_ack(tcb),
+            _ => {}
+        }
+    }
+
+    /// Probes an idle connection, or gives it up once the peer failed to
+    /// answer enough probes
+    fn on_keepalive_timer(&mut self, tcb: &mut TCB) {
+        match tcb.keepalive_deadline() {
+            Some(deadline) if Instant::now() >= deadline => {}
+            _ => return,
+        }
+        if tcb.keepalive_probes >= tcb.keepalive.unwrap().count {
+            self.abort(tcb);
+            return;
+        }
+
+        // An old sequence number makes the peer answer with an ACK
+        let mut out_tcph = tcb.tcph_new(tcb.snd_nxt - 1);
+        out_tcph.acknowledgment_number = tcb.rcv_nxt.0;
+        out_tcph.ack = true;
+        self.send_segment(tcb, &mut out_tcph, &[]);
+        tcb.keepalive_probes += 1;
+    }
+
+    /// Sends the segment at `index` in the retransmission queue again, with
+    /// the acknowledgment and window brought up to date
+    fn retransmit(&mut self, tcb: &mut TCB, index: usize) {
+        let seg = &mut tcb.retransmission_queue[index];
+        seg.retransmitted = true;
+        let mut tcph = seg.tcph.clone();
+        let data = seg.data.clone();
+        if tcph.ack {
+            tcph.acknowledgment_number = tcb.rcv_nxt.0;
+        }
+        if !tcph.syn {
+            tcph.window_size = tcb.advertise_window();
+            tcb.write_timestamp(&mut tcph);
+        }
+        self.send_segment(tcb, &mut tcph, &data);
+    }
+
+    /// Sends a window probe: the next octet of the send buffer, past the
+    /// zero window. An earlier probe the peer didn't take is sent again.
+    fn send_probe(&mut self, tcb: &mut TCB) {
+        if !tcb.retransmission_queue.is_empty() {
+            self.retransmit(tcb, 0);
+            return;
+        }
+        if let Some(octet) = tcb.snd_buf.pop_front() {
+            tcb.snd_push = tcb.snd_push.saturating_sub(1);
+            let mut out_tcph = tcb.tcph_new(tcb.snd_nxt);
+            out_tcph.acknowledgment_number = tcb.rcv_nxt.0;
+            out_tcph.ack = true;
+            self.send_reliable(tcb, out_tcph, vec![octet]);
+        }
+    }
+
+    /// Runs congestion control and fast recovery for an acceptable ACK that
+    /// acknowledged `acked` new octets
+    fn on_ack(&mut self, tcb: &mut TCB, acked: u32, dup_ack: bool) {
+        let mss = tcb.snd_mss as u32;
+        let flight_size = tcb.snd_nxt - tcb.snd_una;
+        let rtt = tcb.rtt.srtt().unwrap_or_else(|| tcb.rtt.rto());
+        if dup_ack {
+            tcb.dup_acks += 1;
+        } else if acked > 0 {
+            tcb.dup_acks = 0;
+        }
+
+        match tcb.recovery_point {
+            // Everything that was outstanding when recovery began is acked
+            Some(recovery_point) if tcb.snd_una >= recovery_point => {
+                tcb.recovery_point = None;
+                tcb.cwnd_inflation = 0;
+                if std::mem::take(&mut tcb.rto_recovery) {
+                    tcb.cc.on_ack(acked, mss, rtt);
+                } else {
+                    tcb.cc.on_recovery_end(flight_size, mss);
+                }
+            }
+            // Slow start after a timeout goes on while the holes are filled
+            Some(_) if tcb.rto_recovery => {
+                if acked > 0 {
+                    tcb.cc.on_ack(acked, mss, rtt);
                 }
+            }
+            // The SACK scoreboard tells what to retransmit, see below
+            Some(_) if tcb.sack_permitted => {}
+            // NewReno fast recovery (RFC 6582 3.2)
+            Some(_) => {
+                if dup_ack {
+                    // Another segment has left the network
+                    tcb.cwnd_inflation += mss;
+                } else if acked > 0 {
+                    // A partial acknowledgment reveals the next hole
+                    tcb.cwnd_inflation = tcb.cwnd_inflation.saturating_sub(acked);
+                    if acked >= mss {
+                        tcb.cwnd_inflation += mss;
+                    }
+                    self.retransmit(tcb, 0);
+                }
+            }
+            None => {
+                if acked > 0 {
+                    tcb.cc.on_ack(acked, mss, rtt);
+                }
+                if tcb.loss_detected() {
+                    // Segments sent by limited transmit don't count (RFC 3042 2)
+                    let limited = flight_size
+                        .saturating_sub(tcb.cc.cwnd())
+                        .min(tcb.limited_transmit());
+                    tcb.recovery_point = Some(tcb.snd_nxt);
+                    tcb.cc.on_loss(flight_size - limited, mss);
+                    if tcb.sack_permitted {
+                        for seg in &mut tcb.retransmission_queue {
+                            seg.recovery_rexmit = false;
+                        }
+                        tcb.retransmission_queue[0].recovery_rexmit = true;
+                    } else {
+                        // The segments behind the three duplicate ACKs have
+                        // left the network
+                        tcb.cwnd_inflation = DUP_THRESH as u32 * mss;
+                    }
+                    // Fast retransmit, whether or not IsLost() holds for the
+                    // first hole yet (RFC 6675 5 step 4.3)
+                    self.retransmit(tcb, 0);
+                }
+            }
+        }
+
+        if tcb.sack_permitted && tcb.recovery_point.is_some() {
+            self.recover(tcb);
+        }
+    }
+
+    /// SACK based loss recovery (RFC 6675): retransmits lost segments as far
+    /// as the congestion window allows
+    fn recover(&mut self, tcb: &mut TCB) {
+        while let Some(index) = tcb.next_lost_segment() {
+            let seg = &tcb.retransmission_queue[index];
+            let len = seg_len(&seg.tcph, &seg.data);
+            if tcb.pipe() + len > tcb.cwnd() {
+                break;
+            }
+            tcb.retransmission_queue[index].recovery_rexmit = true;
+            self.retransmit(tcb, index);
+        }
+    }
+
+    /// Gives up on a connection, telling the service and the peer
+    fn abort(&mut self, tcb: &mut TCB) {
+        if ![TCPState::Listen, TCPState::SynSent].contains(&tcb.state) {
+            let mut out_tcph = tcb.tcph_new(tcb.snd_nxt);
+            out_tcph.rst = true;
+            self.send_segment(tcb, &mut out_tcph, &[]);
+        }
+        tcb.state = TCPState::Closed;
+        tcb.svc.on_reset();
+    }
 
-                let seq = 0;
-                let ack = in_tcph.sequence_number + seg_len;
+    /// Segment arrives for a connection that doesn't exist, ie. CLOSED state
+    fn closed_segment_arrives(
+        &mut self,
+        in_iph: &Ipv4Header,
+        in_tcph: &TcpHeader,
+        in_tcppld: &[u8],
+    ) {
+        if in_tcph.rst {
+            return;
+        }
 
-                let mut out_tcph = tcph_reply(&in_tcph, seq, self.window_size);
-                out_tcph.acknowledgment_number = ack;
+        if in_tcph.ack {
+            self.reset_simple(in_tcph, in_iph);
+            return;
+        }
 
-                out_tcph.ack = true;
-                out_tcph.rst = true;
+        // RFC 793 uses sequence number zero when there is no ACK to take it from
+        let seq = SeqNum(0);
+        let ack = SeqNum(in_tcph.sequence_number) + seg_len(in_tcph, in_tcppld);
 
-                self.send_tcph(&mut out_tcph, &in_iph, &[]);
+        let mut out_tcph = tcph_reply(in_tcph, seq, 0);
+        out_tcph.acknowledgment_number = ack.0;
+
+        out_tcph.ack = true;
+        out_tcph.rst = true;
+
+        self.send_tcph(&mut out_tcph, in_iph, &[]);
+    }
+
+    fn segment_arrives(
+        &mut self,
+        tcb: &mut TCB,
+        in_iph: &Ipv4Header,
+        in_tcph: &TcpHeader,
+        in_tcppld: &[u8],
+    ) {
+        let seg_len = seg_len(in_tcph, in_tcppld);
+        let seg_seq = SeqNum(in_tcph.sequence_number);
+        let seg_ack = SeqNum(in_tcph.acknowledgment_number);
+        let options = Options::parse(in_tcph);
+
+        // Whatever arrives shows the peer is alive
+        tcb.last_received = Instant::now();
+        tcb.keepalive_probes = 0;
+
+        match &tcb.state {
+            TCPState::Closed => {
+                self.closed_segment_arrives(in_iph, in_tcph, in_tcppld);
                 return;
             }
             TCPState::Listen => {
@@ -190,34 +1486,36 @@ impl TCP {
                 }
 
                 if in_tcph.ack {
-                    self.reset_simple(&in_tcph, &in_iph);
+                    self.reset_simple(in_tcph, in_iph);
                     return;
                 }
 
                 if in_tcph.syn {
-                    self.tcb.rcv_nxt = in_tcph.sequence_number + 1;
-                    self.tcb.irs = in_tcph.sequence_number;
-                    self.tcb.iss = 123445;
+                    tcb.foreign_socket = Some((in_iph.source, in_tcph.source_port));
+                    tcb.rcv_nxt = seg_seq + 1;
+                    tcb.irs = seg_seq;
+                    tcb.iss = self.isn_gen.isn(&Quad {
+                        local: tcb.local_socket,
+                        foreign: (in_iph.source, in_tcph.source_port),
+                    });
+                    tcb.rcv_mss = self.mss();
+                    tcb.negotiate(&options);
 
                     let mut out_tcph = tcph_reply(
-                        &in_tcph,
-                        self.tcb.iss, // sequence_number
-                        self.window_size,
+                        in_tcph,
+                        tcb.iss, // sequence_number
+                        tcb.syn_window(),
                     );
-                    out_tcph.acknowledgment_number = self.tcb.rcv_nxt;
+                    tcb.syn_options().write(&mut out_tcph);
+                    out_tcph.acknowledgment_number = tcb.rcv_nxt.0;
                     out_tcph.syn = true;
                     out_tcph.ack = true;
 
-                    self.send_tcph(&mut out_tcph, &in_iph, &[]);
-
-                    self.tcb.snd_nxt = self.tcb.iss + 1;
-                    self.tcb.snd_una = self.tcb.iss;
+                    self.send_reliable(tcb, out_tcph, Vec::new());
+                    tcb.snd_una = tcb.iss;
 
-                    self.tcb.state = TCPState::SynRecvd;
-
-                    // TODO - Fill in foreign socket
+                    tcb.state = TCPState::SynRecvd;
                 }
-                eprintln!("SynRecvd");
 
                 // In case any segment fall through above checks,
                 // drop them
@@ -226,10 +1524,8 @@ impl TCP {
             TCPState::SynSent => {
                 let mut is_ack_acceptable = false;
                 if in_tcph.ack {
-                    if in_tcph.acknowledgment_number <= self.tcb.iss
-                        || in_tcph.acknowledgment_number > self.tcb.snd_nxt
-                    {
-                        self.reset_simple(&in_tcph, &in_iph);
+                    if seg_ack <= tcb.iss || seg_ack > tcb.snd_nxt {
+                        self.reset_simple(in_tcph, in_iph);
                         return;
                     }
 
@@ -238,9 +1534,9 @@ impl TCP {
 
                 if in_tcph.rst {
                     if is_ack_acceptable {
-                        self.tcb.svc.on_reset();
+                        tcb.svc.on_reset();
 
-                        self.tcb.state = TCPState::Closed;
+                        tcb.state = TCPState::Closed;
                         return;
                     }
                     // ignore rst on unacceptable ack
@@ -250,47 +1546,50 @@ impl TCP {
                 debug_assert!(is_ack_acceptable || (!in_tcph.ack && !in_tcph.rst));
 
                 if in_tcph.syn {
-                    self.tcb.rcv_nxt = in_tcph.sequence_number + 1;
-                    self.tcb.irs = in_tcph.sequence_number;
+                    tcb.rcv_nxt = seg_seq + 1;
+                    tcb.irs = seg_seq;
+                    tcb.negotiate(&options);
+                    // The window on our SYN was relative to nothing yet
+                    tcb.rcv_adv = tcb.rcv_nxt + tcb.rcv_buf_free();
                     if in_tcph.ack {
-                        self.tcb.snd_una = in_tcph.acknowledgment_number;
+                        tcb.snd_una = seg_ack;
                     }
 
-                    // remove segments from retransmission queue
-                    {
-                        let snd_una = self.tcb.snd_una;
-                        self.tcb.retransmission_queue.retain(|(_iph, tcph, pld)| {
-                            let mut last_seq = tcph.sequence_number + (pld.len() as u32) - 1;
-                            if tcph.syn {
-                                last_seq += 1;
-                            }
-                            if tcph.fin {
-                                last_seq += 1;
-                            }
-                            last_seq >= snd_una
-                        });
-                    }
+                    tcb.remove_acked_segments();
 
-                    if self.tcb.snd_una > self.tcb.iss {
-                        self.tcb.state = TCPState::Estab;
+                    if tcb.snd_una > tcb.iss {
+                        tcb.state = TCPState::Estab;
+                        tcb.snd_wnd = in_tcph.window_size as u32;
+                        tcb.snd_wl1 = seg_seq;
+                        tcb.snd_wl2 = seg_ack;
 
-                        let seq = self.tcb.snd_nxt;
-                        // We don't increment SND.NXT, cos ACK doesn't occupy sequence space
+                        // Text on the SYN is taken as far as the window we
+                        // offered, and acked with it
+                        let len = in_tcppld.len().min(tcb.rcv_wnd() as usize);
+                        tcb.rcv_buf.extend(&in_tcppld[..len]);
+                        tcb.rcv_nxt += len as u32;
 
-                        let mut out_tcph = tcph_reply(&in_tcph, seq, self.window_size);
-                        out_tcph.acknowledgment_number = self.tcb.rcv_nxt;
-                        out_tcph.ack = true;
+                        // We don't increment SND.NXT, cos ACK doesn't occupy sequence space
+                        self.send_ack(tcb);
 
-                        self.send_tcph(&mut out_tcph, &in_iph, &[]);
+                        tcb.svc.on_open(tcb.quad());
+                        let response = tcb.svc.on_connect(&[]);
+                        self.respond(tcb, response);
+                        self.deliver(tcb);
                         return;
                     } else {
-                        self.tcb.state = TCPState::SynRecvd;
-                        let seq = self.tcb.iss;
-                        let mut out_tcph = tcph_reply(&in_tcph, seq, self.window_size);
-                        out_tcph.acknowledgment_number = self.tcb.rcv_nxt;
+                        tcb.state = TCPState::SynRecvd;
+                        let seq = tcb.iss;
+                        let mut out_tcph = tcph_reply(in_tcph, seq, tcb.syn_window());
+                        tcb.syn_options().write(&mut out_tcph);
+                        out_tcph.acknowledgment_number = tcb.rcv_nxt.0;
                         out_tcph.syn = true;
                         out_tcph.ack = true;
-                        self.send_tcph(&mut out_tcph, &in_iph, &[]);
+
+                        // The SYN-ACK replaces our unacked SYN
+                        tcb.retransmission_queue.clear();
+                        tcb.snd_nxt = tcb.iss;
+                        self.send_reliable(tcb, out_tcph, Vec::new());
                         return;
                     }
                 }
@@ -313,63 +1612,65 @@ impl TCP {
             TCPState::LastAck,
             TCPState::TimeWait,
         ]
-        .contains(&self.tcb.state)
+        .contains(&tcb.state)
         {
             let is_seg_acceptable = {
-                let case1 = seg_len == 0
-                    && self.window_size == 0
-                    && in_tcph.sequence_number == self.tcb.rcv_nxt;
+                let rcv_wnd = tcb.rcv_wnd();
+                let case1 = seg_len == 0 && rcv_wnd == 0 && seg_seq == tcb.rcv_nxt;
                 let case2 = seg_len == 0
-                    && self.window_size > 0
-                    && self.tcb.rcv_nxt <= in_tcph.sequence_number
-                    && in_tcph.sequence_number < (self.tcb.rcv_nxt + self.window_size as u32);
-
-                // Note: We are checking if part of the packet coincides with top end of receive
-                // window.
-                // Slightly tweaked from the original spec.
-                // ie, we will drop packets if RCV.NXT octet is not in the packet.
+                    && rcv_wnd > 0
+                    && tcb.rcv_nxt <= seg_seq
+                    && seg_seq < (tcb.rcv_nxt + rcv_wnd);
+
+                // Either the first or the last octet of the segment must be
+                // in the window. Segments ahead of RCV.NXT are reassembled.
+                let rcv_wnd_end = tcb.rcv_adv;
                 let case3 = seg_len > 0
-                    && self.window_size > 0
-                    && in_tcph.sequence_number <= self.tcb.rcv_nxt
-                    && self.tcb.rcv_nxt <= in_tcph.sequence_number + seg_len - 1
-                    && in_tcph.sequence_number + seg_len - 1
-                        < self.tcb.rcv_nxt + self.window_size as u32;
-                dbg!(case1) || dbg!(case2) || dbg!(case3)
+                    && rcv_wnd > 0
+                    && ((tcb.rcv_nxt <= seg_seq && seg_seq < rcv_wnd_end)
+                        || (tcb.rcv_nxt < seg_seq + seg_len
+                            && seg_seq + seg_len - 1 < rcv_wnd_end));
+                case1 || case2 || case3
             };
             if !is_seg_acceptable && !in_tcph.rst {
-                println!("HERE");
-                let seq = self.tcb.snd_nxt;
-                let ack = self.tcb.rcv_nxt;
-                let mut out_tcph = tcph_reply(&in_tcph, seq, self.window_size);
-                out_tcph.acknowledgment_number = ack;
-                out_tcph.ack = true;
-                self.send_tcph(&mut out_tcph, &in_iph, &[]);
+                self.send_ack(tcb);
                 // ack the old segment, ignore data, return
                 return;
             }
+
+            // Remember the timestamp to echo, from segments that start at
+            // or before what we acked (RFC 7323 4.3)
+            if let Some((tsval, _)) = options.timestamp {
+                if tcb.timestamps
+                    && SeqNum(tsval) >= SeqNum(tcb.ts_recent)
+                    && seg_seq <= tcb.rcv_nxt
+                {
+                    tcb.ts_recent = tsval;
+                }
+            }
         }
 
         // Check RST bit
         if in_tcph.rst {
-            match &self.tcb.state {
+            match &tcb.state {
                 TCPState::SynRecvd => {
-                    if self.tcb.open_mode == OpenMode::Passive {
-                        self.tcb.state = TCPState::Listen;
+                    if tcb.open_mode == OpenMode::Passive {
+                        tcb.state = TCPState::Listen;
                         return;
                     } else {
-                        self.tcb.state = TCPState::Closed;
-                        self.tcb.svc.on_close(&[]);
+                        tcb.state = TCPState::Closed;
+                        tcb.svc.on_close(&[]);
                         return;
                     }
                 }
                 TCPState::Estab | TCPState::FinWait1 | TCPState::FinWait2 | TCPState::CloseWait => {
-                    self.tcb.state = TCPState::Closed;
-                    self.tcb.svc.on_close(&[]);
+                    tcb.state = TCPState::Closed;
+                    tcb.svc.on_close(&[]);
                     return;
                 }
                 TCPState::Closing | TCPState::LastAck | TCPState::TimeWait => {
-                    self.tcb.state = TCPState::Closed;
-                    self.tcb.svc.on_close(&[]);
+                    tcb.state = TCPState::Closed;
+                    tcb.svc.on_close(&[]);
                     return;
                 }
                 _ => {}
@@ -383,14 +1684,19 @@ impl TCP {
         }
 
         // ACK is set
-        if TCPState::SynRecvd == self.tcb.state {
-            if self.tcb.snd_una <= in_tcph.acknowledgment_number
-                && in_tcph.acknowledgment_number <= self.tcb.snd_nxt
-            {
-                self.tcb.state = TCPState::Estab;
+        if TCPState::SynRecvd == tcb.state {
+            if tcb.snd_una <= seg_ack && seg_ack <= tcb.snd_nxt {
+                tcb.state = TCPState::Estab;
+                tcb.snd_wnd = (in_tcph.window_size as u32) << tcb.snd_wscale;
+                tcb.snd_wl1 = seg_seq;
+                tcb.snd_wl2 = seg_ack;
+
+                tcb.svc.on_open(tcb.quad());
+                let response = tcb.svc.on_connect(&[]);
+                self.respond(tcb, response);
             // continue processing. don't return here
             } else {
-                self.reset_simple(&in_tcph, &in_iph);
+                self.reset_simple(in_tcph, in_iph);
             }
         }
         if [
@@ -398,146 +1704,160 @@ impl TCP {
             TCPState::FinWait1,
             TCPState::CloseWait,
             TCPState::Closing,
+            TCPState::LastAck,
         ]
-        .contains(&self.tcb.state)
+        .contains(&tcb.state)
         {
-            if self.tcb.snd_una <= in_tcph.acknowledgment_number
-                && in_tcph.acknowledgment_number <= self.tcb.snd_nxt
-            {
-                self.tcb.snd_una = in_tcph.acknowledgment_number;
-                // remove segments from retransmission queue
-                {
-                    let snd_una = self.tcb.snd_una;
-                    self.tcb.retransmission_queue.retain(|(_iph, tcph, pld)| {
-                        let mut last_seq = tcph.sequence_number + (pld.len() as u32) - 1;
-                        if tcph.syn {
-                            last_seq += 1;
-                        }
-                        if tcph.fin {
-                            last_seq += 1;
-                        }
-                        last_seq >= snd_una
-                    });
+            if tcb.snd_una <= seg_ack && seg_ack <= tcb.snd_nxt {
+                // The SYN takes sequence space but is no data
+                let syn_acked = tcb.snd_una == tcb.iss && seg_ack > tcb.iss;
+                let acked = seg_ack - tcb.snd_una - syn_acked as u32;
+                // Duplicate ACK as RFC 5681 defines it
+                let dup_ack = seg_ack == tcb.snd_una
+                    && seg_len == 0
+                    && tcb.snd_una != tcb.snd_nxt
+                    && tcb.snd_wnd > 0
+                    && (in_tcph.window_size as u32) << tcb.snd_wscale == tcb.snd_wnd;
+
+                tcb.snd_una = seg_ack;
+                tcb.remove_acked_segments();
+                if tcb.sack_permitted {
+                    tcb.update_scoreboard(&options.sack);
                 }
 
                 // Update Send Window
-                if self.tcb.snd_wl1 < in_tcph.sequence_number
-                    || self.tcb.snd_wl1 == in_tcph.sequence_number
-                        && self.tcb.snd_wl2 <= in_tcph.acknowledgment_number
-                {
-                    self.tcb.snd_wnd = in_tcph.window_size as u32;
-                    self.tcb.snd_wl1 = in_tcph.sequence_number;
-                    self.tcb.snd_wl2 = in_tcph.acknowledgment_number;
+                if tcb.snd_wl1 < seg_seq || tcb.snd_wl1 == seg_seq && tcb.snd_wl2 <= seg_ack {
+                    tcb.snd_wnd = (in_tcph.window_size as u32) << tcb.snd_wscale;
+                    tcb.snd_wl1 = seg_seq;
+                    tcb.snd_wl2 = seg_ack;
                 }
-            } else if in_tcph.acknowledgment_number < self.tcb.snd_una {
+
+                self.on_ack(tcb, acked, dup_ack);
+            } else if seg_ack < tcb.snd_una {
                 // ignore
-            } else if in_tcph.acknowledgment_number > self.tcb.snd_nxt {
-                println!("HERE2");
-                let seq = self.tcb.snd_nxt;
-                let ack = self.tcb.rcv_nxt;
-                let mut out_tcph = tcph_reply(&in_tcph, seq, self.window_size);
-                out_tcph.acknowledgment_number = ack;
-                out_tcph.ack = true;
-                self.send_tcph(&mut out_tcph, &in_iph, &[]);
+            } else if seg_ack > tcb.snd_nxt {
+                self.send_ack(tcb);
                 // ack the old segment, ignore data, return
                 return;
             }
 
-            if self.tcb.state == TCPState::FinWait1 {
-                // If our FIN was acked, enter FinWait2 and continue processing
+            // Acked data or a wider window may let more of the send buffer out
+            self.send_pending(tcb);
 
-                // FIN is the last unacknowledged
-                let fin_seq = self.tcb.snd_una;
-                if in_tcph.acknowledgment_number == fin_seq {
-                    self.tcb.state = TCPState::FinWait2;
+            if tcb.state == TCPState::FinWait1 {
+                // If our FIN was acked, enter FinWait2 and continue processing
+                if tcb.fin_acked(seg_ack) {
+                    tcb.state = TCPState::FinWait2;
                 }
             }
 
-            if self.tcb.state == TCPState::FinWait2 {
+            if tcb.state == TCPState::FinWait2 {
                 // donot delete tcb
             }
 
-            if self.tcb.state == TCPState::Closing {
+            if tcb.state == TCPState::Closing {
                 // If our FIN was acked, enter TimeWait, else ignore segment
-
-                // FIN is the last unacknowledged
-                let fin_seq = self.tcb.snd_una;
-                if in_tcph.acknowledgment_number == fin_seq {
-                    self.tcb.state = TCPState::FinWait2;
+                if tcb.fin_acked(seg_ack) {
+                    tcb.state = TCPState::TimeWait;
+                    tcb.timer_pending = Some(Timer::TimeWait(Instant::now()));
                 } else {
                     return;
                 }
             }
         }
 
-        if TCPState::LastAck == self.tcb.state {
+        if TCPState::LastAck == tcb.state {
             // If our FIN was acked, enter Closed state
-
-            // FIN is the last unacknowledged
-            let fin_seq = self.tcb.snd_una;
-            if in_tcph.acknowledgment_number == fin_seq {
-                self.tcb.state = TCPState::Closed;
+            if tcb.fin_acked(seg_ack) {
+                tcb.state = TCPState::Closed;
+                tcb.svc.on_close(&[]);
             }
         }
 
-        if TCPState::TimeWait == self.tcb.state {
+        if TCPState::TimeWait == tcb.state {
             // If it is a retransmission of remote FIN,
             // ACK it and restart 2MSL timeout
 
             if in_tcph.fin {
                 // We don't increment SND.NXT, cos ACK doesn't occupy sequence space
-                let seq = self.tcb.snd_nxt;
-                let ack = in_tcph.sequence_number + seg_len;
-
-                let mut out_tcph = tcph_reply(&in_tcph, seq, self.window_size);
-                out_tcph.acknowledgment_number = ack;
-                out_tcph.ack = true;
-                self.send_tcph(&mut out_tcph, &in_iph, &[]);
+                self.send_ack(tcb);
 
                 // Restart TimeWait timeout
-                self.tcb.timer_pending = Some(Timer::TimeWait(Instant::now()));
+                tcb.timer_pending = Some(Timer::TimeWait(Instant::now()));
             }
         }
 
         // Process Segment Text
-        if [TCPState::Estab, TCPState::FinWait1, TCPState::FinWait2].contains(&self.tcb.state) {
-            self.tcb.rcv_nxt = in_tcph.sequence_number + seg_len;
+        let mut seg_fin = in_tcph.fin;
+        let mut text_acked = false;
+        if [TCPState::Estab, TCPState::FinWait1, TCPState::FinWait2].contains(&tcb.state) {
+            // Drop whatever lies beyond the receive window
+            let rcv_wnd_end = tcb.rcv_nxt + tcb.rcv_wnd();
+            let mut text = in_tcppld;
+            if seg_seq + text.len() as u32 > rcv_wnd_end {
+                text = &text[..(rcv_wnd_end - seg_seq) as usize];
+                seg_fin = false;
+            }
 
-            let seq = self.tcb.snd_nxt;
-            let ack = self.tcb.rcv_nxt;
-            let mut out_tcph = tcph_reply(&in_tcph, seq, self.window_size);
-            out_tcph.acknowledgment_number = ack;
-            out_tcph.ack = true;
+            if seg_seq > tcb.rcv_nxt {
+                // Out of order, hold it until the gap is filled. ACK right
+                // away so the peer learns about the gap.
+                tcb.reassembly.insert(seg_seq, text, seg_fin);
 
-            if in_tcppld.len() > 0 {
-                let data_to_send = self.tcb.svc.on_receive(in_tcppld);
-                println!("Here");
-                if let Response::Data(data_to_send) = data_to_send {
-                    println!("Sending data");
-                    self.send_tcph(&mut out_tcph, &in_iph, &data_to_send[..]);
-                    self.tcb.snd_nxt += data_to_send.len() as u32;
-                } else if let Response::Close(data_to_send) = data_to_send {
-                    println!("Sending data and closing");
-                    out_tcph.fin = true;
-                    self.send_tcph(&mut out_tcph, &in_iph, &data_to_send[..]);
-                    self.tcb.snd_nxt += data_to_send.len() as u32;
-                    self.tcb.state = TCPState::FinWait1;
-                    return;
-                } else {
-                    println!("Sending nothing");
-                    self.send_tcph(&mut out_tcph, &in_iph, &[]);
+                self.send_ack(tcb);
+                return;
+            }
+
+            // Text that fills a gap is acked at once, so the peer learns
+            // about it quickly (RFC 5681 4.2)
+            let gap = !tcb.reassembly.is_empty();
+
+            // Skip the part we already received
+            let skip = ((tcb.rcv_nxt - seg_seq) as usize).min(text.len());
+            let mut data = text[skip..].to_vec();
+            tcb.rcv_nxt += data.len() as u32;
+
+            // The segment may have filled a gap before reassembled text
+            while let Some(more) = tcb.reassembly.pop(tcb.rcv_nxt) {
+                tcb.rcv_nxt += more.len() as u32;
+                data.extend_from_slice(&more);
+            }
+            if tcb.reassembly.fin_reached(tcb.rcv_nxt) {
+                seg_fin = true;
+            }
+            if seg_fin {
+                tcb.rcv_nxt += 1;
+            }
+
+            if !data.is_empty() {
+                text_acked = true;
+                tcb.rcv_buf.extend(data);
+                if in_tcppld.len() + in_tcph.options_len() >= tcb.rcv_mss {
+                    tcb.unacked_segments += 1;
+                }
+                // Text goes to the service as soon as it is in order, so
+                // pushed text never waits for more (RFC 793 2.8). The
+                // response carries the ACK. Else the ACK waits for one,
+                // but no longer than the delay or a second full-sized
+                // segment (RFC 1122 4.2.3.2).
+                if !self.deliver(tcb) {
+                    if gap || seg_fin || tcb.unacked_segments >= 2 || tcb.ack_delay.is_zero() {
+                        self.send_ack(tcb);
+                    } else if tcb.ack_pending.is_none() {
+                        tcb.ack_pending = Some(Instant::now());
+                    }
                 }
             }
         }
 
-        if in_tcph.fin {
+        if seg_fin {
             if [
                 TCPState::CloseWait,
                 TCPState::Closing,
                 TCPState::LastAck,
                 TCPState::TimeWait,
             ]
-            .contains(&self.tcb.state)
+            .contains(&tcb.state)
             {
                 // Should not occur, since we got a FIN already.
                 // ignore and return
@@ -545,95 +1865,323 @@ impl TCP {
             }
 
             // Check FIN
-            if [TCPState::Closed, TCPState::Listen, TCPState::SynSent].contains(&self.tcb.state) {
+            if [TCPState::Closed, TCPState::Listen, TCPState::SynSent].contains(&tcb.state) {
                 // Do not process FIN cos we can't validate SEG.SEQ
                 // ignore and return
                 return;
             }
 
-            if [TCPState::SynRecvd, TCPState::Estab].contains(&self.tcb.state) {
-                self.tcb.state = TCPState::CloseWait;
-
-                let seq = self.tcb.snd_nxt;
-                let ack = self.tcb.rcv_nxt;
-                let mut out_tcph = tcph_reply(&in_tcph, seq, self.window_size);
-                out_tcph.acknowledgment_number = ack;
-                out_tcph.ack = true;
-                out_tcph.fin = true;
-
-                self.tcb.snd_nxt += 1;
-                if self.tcb.snd_una == self.tcb.snd_nxt-1 {
-                    self.tcb.snd_una += 1;
+            if [TCPState::SynRecvd, TCPState::Estab].contains(&tcb.state) {
+                tcb.state = TCPState::CloseWait;
+                // A response carries the ACK of the FIN
+                if !self.deliver_fin(tcb) && !text_acked {
+                    self.send_ack(tcb);
+                }
+            } else if [TCPState::FinWait1, TCPState::FinWait2].contains(&tcb.state) {
+                // Nothing can be sent anymore, whatever the service responds
+                self.deliver_fin(tcb);
+                if !text_acked {
+                    // ACK the FIN, unless it was already acked along with the text
+                    self.send_ack(tcb);
                 }
-                self.send_tcph(&mut out_tcph, &in_iph, &[]);
-
-                self.tcb.state = TCPState::LastAck;
             }
 
-            if TCPState::FinWait1 == self.tcb.state {
-                // If our FIN was acked, enter Closed state
-
-                // FIN is the last unacknowledged
-                let fin_seq = self.tcb.snd_una;
-                if in_tcph.acknowledgment_number == fin_seq {
-                    self.tcb.state = TCPState::Closing;
-                }
+            if TCPState::FinWait1 == tcb.state {
+                // Our FIN was not acked yet, else we would be in FinWait2 by now
+                tcb.state = TCPState::Closing;
             }
 
-            if TCPState::FinWait2 == self.tcb.state {
-                self.tcb.state = TCPState::TimeWait;
+            if TCPState::FinWait2 == tcb.state {
+                tcb.state = TCPState::TimeWait;
                 // Start time wait timer, turn off other timers
-                self.tcb.timer_pending = Some(Timer::TimeWait(Instant::now()));
+                tcb.timer_pending = Some(Timer::TimeWait(Instant::now()));
             }
 
-            if TCPState::TimeWait == self.tcb.state {
+            if TCPState::TimeWait == tcb.state {
                 // Restart 2MSL timeout
-                self.tcb.timer_pending = Some(Timer::TimeWait(Instant::now()));
+                tcb.timer_pending = Some(Timer::TimeWait(Instant::now()));
             }
         }
-        eprintln!("New State: {:?}", &self.tcb.state);
-        eprintln!("-----");
     }
 
     fn reset_simple(&mut self, in_tcph: &TcpHeader, in_iph: &Ipv4Header) {
-        let seq = in_tcph.acknowledgment_number;
-        let mut out_tcph = tcph_reply(&in_tcph, seq, self.window_size);
+        let seq = SeqNum(in_tcph.acknowledgment_number);
+        let mut out_tcph = tcph_reply(in_tcph, seq, 0);
         out_tcph.rst = true;
 
-        self.send_tcph(&mut out_tcph, &in_iph, &[]);
+        self.send_tcph(&mut out_tcph, in_iph, &[]);
+    }
+
+    /// Passes text from the receive buffer to the service, as much as it can
+    /// take, and sends its responses
+    ///
+    /// Returns whether anything was sent.
+    fn deliver(&mut self, tcb: &mut TCB) -> bool {
+        let mut sent = false;
+        while !tcb.rcv_buf.is_empty() {
+            let len = tcb.svc.receive_capacity().min(tcb.rcv_buf.len());
+            if len == 0 {
+                break;
+            }
+            let data: Vec<u8> = tcb.rcv_buf.drain(..len).collect();
+            let response = tcb.svc.on_receive(&data);
+            sent |= self.respond(tcb, response);
+        }
+        if tcb.fin_undelivered && tcb.rcv_buf.is_empty() {
+            sent |= self.deliver_fin(tcb);
+        }
+        sent
+    }
+
+    /// Tells the service that the peer finished sending, once it has taken
+    /// all text before the FIN, and sends its response
+    ///
+    /// Returns whether anything was sent.
+    fn deliver_fin(&mut self, tcb: &mut TCB) -> bool {
+        tcb.fin_undelivered = !tcb.rcv_buf.is_empty();
+        if tcb.fin_undelivered {
+            return false;
+        }
+        let response = tcb.svc.on_fin();
+        self.respond(tcb, response)
+    }
+
+    /// Sends an empty segment acknowledging everything received so far
+    fn send_ack(&mut self, tcb: &mut TCB) {
+        let mut out_tcph = tcb.tcph_new(tcb.snd_nxt);
+        out_tcph.acknowledgment_number = tcb.rcv_nxt.0;
+        out_tcph.ack = true;
+        self.send_segment(tcb, &mut out_tcph, &[]);
+    }
+
+    /// Sends whatever the service responded with on an established connection
+    ///
+    /// Returns whether anything was sent. Nothing can be sent once our side
+    /// of the connection is closed.
+    fn respond(&mut self, tcb: &mut TCB, response: Response) -> bool {
+        if ![TCPState::Estab, TCPState::CloseWait].contains(&tcb.state) {
+            return false;
+        }
+
+        match response {
+            Response::Data(data) => {
+                tcb.snd_buf.extend(data);
+                self.send_pending(tcb)
+            }
+            Response::Push(data) => {
+                tcb.snd_buf.extend(data);
+                tcb.snd_push = tcb.snd_buf.len();
+                self.send_pending(tcb)
+            }
+            Response::Close(data) => {
+                tcb.snd_buf.extend(data);
+                self.close(tcb)
+            }
+            Response::None => false,
+        }
+    }
+
+    /// Queues our FIN after whatever is left in the send buffer
+    ///
+    /// Returns whether anything was sent.
+    fn close(&mut self, tcb: &mut TCB) -> bool {
+        tcb.fin_pending = true;
+        tcb.state = if tcb.state == TCPState::CloseWait {
+            TCPState::LastAck
+        } else {
+            TCPState::FinWait1
+        };
+        self.send_pending(tcb)
+    }
+
+    /// Sends as much of the send buffer as the windows allow, in segments of
+    /// at most one MSS, followed by the FIN once the buffer is empty
+    ///
+    /// Returns whether anything was sent.
+    fn send_pending(&mut self, tcb: &mut TCB) -> bool {
+        let mut sent = false;
+        while self.send_next(tcb) {
+            sent = true;
+        }
+        if sent {
+            tcb.svc.on_sent();
+        }
+        sent
+    }
+
+    /// Sends the next segment of the send buffer, if the windows and
+    /// Nagle's algorithm allow
+    ///
+    /// Returns whether it was sent.
+    fn send_next(&mut self, tcb: &mut TCB) -> bool {
+        let mut out_tcph = tcb.tcph_unwindowed(tcb.snd_nxt);
+        // The MSS leaves out options, so they take from the segment text
+        let room = tcb.snd_mss.saturating_sub(out_tcph.options_len());
+        let len = tcb
+            .snd_buf
+            .len()
+            .min(room)
+            .min(tcb.usable_window() as usize);
+        let fin = tcb.fin_pending && len == tcb.snd_buf.len();
+        if len == 0 && !fin {
+            tcb.update_persist_timer();
+            return false;
+        }
+        // Closing flushes whatever is left
+        if len < room && !fin && !tcb.nagle_allows() {
+            return false;
+        }
+
+        // Tell the peer not to wait for more once the pushed text or all
+        // we have is in
+        let psh = len == tcb.snd_buf.len() || tcb.snd_push > 0 && len >= tcb.snd_push;
+        tcb.snd_push = tcb.snd_push.saturating_sub(len);
+        let data: Vec<u8> = tcb.snd_buf.drain(..len).collect();
+        out_tcph.window_size = tcb.advertise_window();
+        out_tcph.acknowledgment_number = tcb.rcv_nxt.0;
+        out_tcph.ack = true;
+        out_tcph.psh = psh && len > 0;
+        out_tcph.fin = fin;
+        if fin {
+            tcb.fin_pending = false;
+        }
+        self.send_reliable(tcb, out_tcph, data);
+        true
     }
 
+    /// Sends a segment which occupies sequence space, and keeps it on the
+    /// retransmission queue until it is acknowledged
+    fn send_reliable(&mut self, tcb: &mut TCB, mut out_tcph: TcpHeader, data: Vec<u8>) {
+        self.send_segment(tcb, &mut out_tcph, &data);
+
+        let seg = Segment {
+            tcph: out_tcph,
+            data,
+            sent_at: Instant::now(),
+            retransmitted: false,
+            sacked: false,
+            recovery_rexmit: false,
+            lost: false,
+        };
+        tcb.snd_nxt = seg.end();
+
+        if tcb.retransmission_queue.is_empty() {
+            tcb.timer_pending = Some(Timer::Retransmission(seg.sent_at));
+        }
+        tcb.retransmission_queue.push(seg);
+    }
+
+    /// Sends a segment on the connection described by `tcb`
+    fn send_segment(&mut self, tcb: &mut TCB, out_tcph: &mut TcpHeader, data: &[u8]) {
+        if out_tcph.ack {
+            // Everything received is acknowledged now
+            tcb.ack_pending = None;
+            tcb.unacked_segments = 0;
+        }
+        let (local_addr, _) = tcb.local_socket;
+        let (foreign_addr, _) = tcb.foreign_socket.expect("Foreign socket not known");
+        self.send_to(out_tcph, local_addr, foreign_addr, data);
+    }
+
+    /// Sends a segment in reply to the packet with header `in_iph`
     fn send_tcph(&mut self, out_tcph: &mut TcpHeader, in_iph: &Ipv4Header, data: &[u8]) {
-        let out_iph = iph_reply(&out_tcph, &in_iph, data);
+        // Incoming destination is our source, incoming source is our dest
+        self.send_to(out_tcph, in_iph.destination, in_iph.source, data);
+    }
+
+    fn send_to(
+        &mut self,
+        out_tcph: &mut TcpHeader,
+        source: [u8; 4],
+        destination: [u8; 4],
+        data: &[u8],
+    ) {
+        let out_iph = Ipv4Header::new(
+            out_tcph.header_len() + data.len() as u16, // Payload length
+            IP_TTL,                                    // Time to live.
+            IpTrafficClass::Tcp,                       // Protocol
+            source,
+            destination,
+        );
+        // Segments are sized to the MTU, so this is never expected to happen
+        let len = out_iph.total_len() as usize;
+        if len > self.buf.len() {
+            self.drops.send_failed += 1;
+            return;
+        }
+
         let mut buf = &mut self.buf[..];
+        out_iph.write(&mut buf).expect("Packet fits");
+        out_tcph.checksum = out_tcph
+            .calc_checksum_ipv4(&out_iph, data)
+            .expect("Packet fits");
+        out_tcph.write(&mut buf).expect("Packet fits");
+        buf[..data.len()].copy_from_slice(data);
+
+        self.transmit(len);
+    }
 
-        out_iph.write(&mut buf).unwrap();
+    /// Sends an ICMP message to `destination`
+    fn send_icmp(&mut self, destination: [u8; 4], message: &[u8]) {
+        let out_iph = Ipv4Header::new(
+            message.len() as u16,
+            IP_TTL,
+            IpTrafficClass::Icmp,
+            self.addr,
+            destination,
+        );
+        // The message quotes at most a header and 8 octets of a packet that
+        // fit the MTU
+        let len = out_iph.total_len() as usize;
+        let mut buf = &mut self.buf[..];
+        out_iph.write(&mut buf).expect("Packet fits");
+        buf[..message.len()].copy_from_slice(message);
 
-        out_tcph.checksum = out_tcph.calc_checksum_ipv4(&out_iph, data).unwrap();
-        out_tcph.write(&mut buf).unwrap();
+        self.transmit(len);
+    }
 
-        let mut reader = data;
-        std::io::copy(&mut reader, &mut buf).unwrap();
+    /// Hands the first `len` bytes of the buffer to the device, rather than
+    /// the whole buffer. A packet the device doesn't take is lost like any
+    /// other. If the device can't send anymore, `tick` fails too.
+    fn transmit(&mut self, len: usize) {
+        if let Err(e) = self.iface.send(&self.buf[..len]) {
+            self.drops.send_failed += 1;
+            if !is_transient(&e) && self.send_error.is_none() {
+                self.send_error = Some(e);
+            }
+        }
+    }
+}
 
-        self.iface.send(&self.buf).unwrap();
+/// Whether a failed send may succeed later: the device is busy, out of
+/// buffers, or refused just this packet
+fn is_transient(e: &io::Error) -> bool {
+    [
+        ErrorKind::WouldBlock,
+        ErrorKind::Interrupted,
+        ErrorKind::InvalidInput,
+    ]
+    .contains(&e.kind())
+        || e.raw_os_error() == Some(libc::ENOBUFS)
+}
+
+/// Sequence space occupied by a segment
+fn seg_len(tcph: &TcpHeader, pld: &[u8]) -> u32 {
+    let mut seg_len = pld.len() as u32;
+    if tcph.syn {
+        seg_len += 1;
+    }
+    if tcph.fin {
+        seg_len += 1;
     }
+    seg_len
 }
 
-fn tcph_reply(in_tcph: &TcpHeader, seq_num: u32, window_size: u16) -> TcpHeader {
+fn tcph_reply(in_tcph: &TcpHeader, seq_num: SeqNum, window_size: u16) -> TcpHeader {
     TcpHeader::new(
         in_tcph.destination_port, // Incoming destination is our source
         in_tcph.source_port,      // Incoming source is our dest
-        seq_num,                  // Sequence Number.
+        seq_num.0,                // Sequence Number.
         window_size,              // Window size.
     )
 }
-
-fn iph_reply(out_tcph: &TcpHeader, in_iph: &Ipv4Header, data: &[u8]) -> Ipv4Header {
-    Ipv4Header::new(
-        out_tcph.header_len() + data.len() as u16, // Payload length
-        IP_TTL,                                    // Time to live.
-        IpTrafficClass::Tcp,                       // Protocol
-        in_iph.destination,                        // Incoming destination is our source
-        in_iph.source,                             // Incoming source is our dest
-    )
-}
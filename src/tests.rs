@@ -0,0 +1,303 @@
+//! Two stacks talking to each other over a `PipeDevice` pair, with the
+//! `EchoServer` as the peer
+
+use crate::device::{Device, DeviceCapabilities, PipeDevice};
+use crate::echo_server::EchoServer;
+use crate::tcp::{Quad, Response, Service, TCPState, TCP};
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+static SERVER_ADDR: [u8; 4] = [10, 0, 0, 2];
+static CLIENT_ADDR: [u8; 4] = [10, 0, 0, 1];
+static ECHO_PORT: u16 = 1000;
+
+/// Records what happens on the client side of a connection
+#[derive(Default)]
+struct Events {
+    connected: bool,
+    received: Vec<u8>,
+    reset: bool,
+    closed: bool,
+}
+
+/// Says hello to the echo server, and closes once the echo is back
+struct Hello(Rc<RefCell<Events>>);
+
+impl Service for Hello {
+    fn on_connect(&mut self, _data: &[u8]) -> Response {
+        self.0.borrow_mut().connected = true;
+        Response::None
+    }
+
+    fn on_receive(&mut self, data: &[u8]) -> Response {
+        let mut events = self.0.borrow_mut();
+        events.received.extend_from_slice(data);
+        if events.received.ends_with(b"Echo :hello") {
+            Response::Close(Vec::new())
+        } else if events.received.ends_with(b"Welcome to echo server!") {
+            Response::Data(b"hello".to_vec())
+        } else {
+            Response::None
+        }
+    }
+
+    fn on_reset(&mut self) {
+        self.0.borrow_mut().reset = true;
+    }
+
+    fn on_close(&mut self, _data: &[u8]) {
+        self.0.borrow_mut().closed = true;
+    }
+}
+
+/// Sends `len` octets to the echo server right away, and collects the echo
+struct Bulk(Rc<RefCell<Events>>, usize);
+
+impl Service for Bulk {
+    fn on_connect(&mut self, _data: &[u8]) -> Response {
+        self.0.borrow_mut().connected = true;
+        Response::Data(vec![b'x'; self.1])
+    }
+
+    fn on_receive(&mut self, data: &[u8]) -> Response {
+        self.0.borrow_mut().received.extend_from_slice(data);
+        Response::None
+    }
+
+    fn on_reset(&mut self) {
+        self.0.borrow_mut().reset = true;
+    }
+
+    fn on_close(&mut self, _data: &[u8]) {
+        self.0.borrow_mut().closed = true;
+    }
+}
+
+/// Loses the segments with text that are sent at the given positions,
+/// counting from 0, and passes on everything else
+struct Lossy {
+    inner: PipeDevice,
+    lose: Vec<usize>,
+    sent: usize,
+    strip_sack: bool,
+}
+
+impl Lossy {
+    fn new(inner: PipeDevice, lose: &[usize]) -> Self {
+        Lossy {
+            inner,
+            lose: lose.to_vec(),
+            sent: 0,
+            strip_sack: false,
+        }
+    }
+
+    /// Also blanks out the SACK blocks of received segments, so that the
+    /// duplicate ACKs are all there is to go by
+    fn without_sack_blocks(mut self) -> Self {
+        self.strip_sack = true;
+        self
+    }
+}
+
+/// Overwrites the SACK option of a packet with NOPs, leaving the checksum
+/// wrong
+fn strip_sack_blocks(packet: &mut [u8]) {
+    let ip_len = (packet[0] & 0xf) as usize * 4;
+    let tcp = &mut packet[ip_len..];
+    let header_len = (tcp[12] >> 4) as usize * 4;
+    let mut i = 20;
+    while i < header_len {
+        match tcp[i] {
+            0 => break,
+            1 => i += 1,
+            kind => {
+                let len = (tcp[i + 1] as usize).max(2);
+                if kind == 5 {
+                    tcp[i..i + len].iter_mut().for_each(|b| *b = 1);
+                }
+                i += len;
+            }
+        }
+    }
+}
+
+impl Device for Lossy {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.recv(buf)?;
+        if self.strip_sack {
+            strip_sack_blocks(&mut buf[..len]);
+        }
+        Ok(len)
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<usize> {
+        // Bare ACKs are the headers with at most 40 octets of options
+        if packet.len() > 80 {
+            self.sent += 1;
+            if self.lose.contains(&(self.sent - 1)) {
+                return Ok(packet.len());
+            }
+        }
+        self.inner.send(packet)
+    }
+
+    /// Stripped packets pass as long as checksums go unchecked
+    fn capabilities(&self) -> DeviceCapabilities {
+        DeviceCapabilities {
+            checksum_offload: self.strip_sack,
+            ..self.inner.capabilities()
+        }
+    }
+
+    fn poll(&mut self, timeout: Option<Duration>) -> io::Result<bool> {
+        self.inner.poll(timeout)
+    }
+}
+
+fn echo_server(device: PipeDevice) -> TCP<PipeDevice> {
+    let mut server = TCP::with_iface(device, SERVER_ADDR);
+    server.listen(ECHO_PORT, Box::new(|| Box::new(EchoServer)));
+    server
+}
+
+/// Polls both stacks until `done`, failing after a few seconds
+fn run<C: Device>(
+    server: &mut TCP<PipeDevice>,
+    client: &mut TCP<C>,
+    mut done: impl FnMut(&TCP<PipeDevice>, &TCP<C>) -> bool,
+) {
+    let start = Instant::now();
+    while !done(server, client) {
+        assert!(start.elapsed() < Duration::from_secs(10), "Timed out");
+        server.poll().unwrap();
+        client.poll().unwrap();
+        // Delayed ACKs and retransmissions wait for their timers
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+fn server_quad(quad: &Quad) -> Quad {
+    Quad {
+        local: quad.foreign,
+        foreign: quad.local,
+    }
+}
+
+#[test]
+fn handshake_data_and_teardown() {
+    let (a, b) = PipeDevice::pair();
+    let mut server = echo_server(a);
+    let mut client = TCP::with_iface(b, CLIENT_ADDR);
+
+    let events = Rc::new(RefCell::new(Events::default()));
+    let quad = client.connect((SERVER_ADDR, ECHO_PORT), Box::new(Hello(events.clone())));
+    assert_eq!(client.state(&quad), Some(TCPState::SynSent));
+
+    run(&mut server, &mut client, |server, _| {
+        server.state(&server_quad(&quad)) == Some(TCPState::Estab)
+    });
+    assert!(events.borrow().connected);
+    assert_eq!(client.state(&quad), Some(TCPState::Estab));
+
+    // The client closes first, so it waits in TIME-WAIT while the server is
+    // done
+    run(&mut server, &mut client, |server, client| {
+        server.state(&server_quad(&quad)).is_none()
+            && client.state(&quad) == Some(TCPState::TimeWait)
+    });
+    let events = events.borrow();
+    assert_eq!(events.received, b"Welcome to echo server!Echo :hello");
+    assert!(!events.reset);
+    assert!(!events.closed);
+}
+
+#[test]
+fn bulk_transfer() {
+    let (a, b) = PipeDevice::pair();
+    let mut server = echo_server(a);
+    let mut client = TCP::with_iface(b, CLIENT_ADDR);
+
+    // Several times the windows, so they have to open and close
+    let len = 100_000;
+    let events = Rc::new(RefCell::new(Events::default()));
+    client.connect(
+        (SERVER_ADDR, ECHO_PORT),
+        Box::new(Bulk(events.clone(), len)),
+    );
+
+    let echoed = |events: &Events| events.received.iter().filter(|&&c| c == b'x').count();
+    run(&mut server, &mut client, |_, _| {
+        echoed(&events.borrow()) == len
+    });
+    assert!(events
+        .borrow()
+        .received
+        .starts_with(b"Welcome to echo server!"));
+    assert_eq!(server.drops().send_failed, 0);
+    assert_eq!(client.drops().send_failed, 0);
+}
+
+#[test]
+fn connection_refused() {
+    let (a, b) = PipeDevice::pair();
+    let mut server = echo_server(a);
+    let mut client = TCP::with_iface(b, CLIENT_ADDR);
+
+    let events = Rc::new(RefCell::new(Events::default()));
+    let quad = client.connect(
+        (SERVER_ADDR, ECHO_PORT + 1),
+        Box::new(Hello(events.clone())),
+    );
+    run(&mut server, &mut client, |_, _| events.borrow().reset);
+    assert!(!events.borrow().connected);
+    assert_eq!(client.state(&quad), None);
+}
+
+/// Sends `len` octets from a client on the device made by `lossy`, and
+/// returns how long it took until they were all echoed
+fn lossy_transfer(len: usize, lossy: impl FnOnce(PipeDevice) -> Lossy) -> Duration {
+    let (a, b) = PipeDevice::pair();
+    let mut server = echo_server(a);
+    let mut client = TCP::with_iface(lossy(b), CLIENT_ADDR);
+    // Every segment is acknowledged at once, so only losses slow it down
+    server.set_ack_delay(Duration::ZERO);
+    client.set_ack_delay(Duration::ZERO);
+
+    let events = Rc::new(RefCell::new(Events::default()));
+    client.connect(
+        (SERVER_ADDR, ECHO_PORT),
+        Box::new(Bulk(events.clone(), len)),
+    );
+    let start = Instant::now();
+    let echoed = |events: &Events| events.received.iter().filter(|&&c| c == b'x').count();
+    run(&mut server, &mut client, |_, _| {
+        echoed(&events.borrow()) == len
+    });
+    start.elapsed()
+}
+
+#[test]
+fn sack_recovers_several_losses_in_a_window() {
+    // Both holes are filled in fast recovery, well before the first RTO
+    let elapsed = lossy_transfer(30_000, |b| Lossy::new(b, &[9, 10]));
+    assert!(elapsed < Duration::from_millis(500), "took {:?}", elapsed);
+}
+
+#[test]
+fn sack_recovers_from_a_timeout_at_once() {
+    // Too few segments follow the losses for fast retransmit, so it takes one
+    // RTO, after which all the holes are filled without further timeouts
+    let elapsed = lossy_transfer(30_000, |b| Lossy::new(b, &[9, 10, 11, 12]));
+    assert!(elapsed < Duration::from_millis(1500), "took {:?}", elapsed);
+}
+
+#[test]
+fn fast_retransmit_without_sack_blocks() {
+    // SACK is permitted but no blocks come, so IsLost() never holds and the
+    // third duplicate ACK alone has to retransmit
+    let elapsed = lossy_transfer(30_000, |b| Lossy::new(b, &[9]).without_sack_blocks());
+    assert!(elapsed < Duration::from_millis(500), "took {:?}", elapsed);
+}